@@ -0,0 +1,55 @@
+//! Compares encoding a batch of documents into fresh vectors with appending
+//! them to one reused buffer. The corpus is the crate's own documentation,
+//! repeated to a few hundred KB per document. BPE merging dominates the
+//! timings, so only compare runs made with the real `o200k_base` vocab.
+//!
+//! Run with `cargo bench --bench encode_into`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use openai_harmony::{load_harmony_encoding, AllowedSpecial, HarmonyEncodingName};
+
+const ITERATIONS: u32 = 10;
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let encoding = load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap();
+    let documents: Vec<String> = [
+        include_str!("../README.md"),
+        include_str!("../docs/format.md"),
+        include_str!("../docs/python.md"),
+        include_str!("../docs/rust.md"),
+    ]
+    .iter()
+    .map(|text| text.repeat(200_000 / text.len() + 1))
+    .collect();
+    println!(
+        "corpus: {} documents, {} KB",
+        documents.len(),
+        documents.iter().map(String::len).sum::<usize>() / 1024
+    );
+
+    let fresh = time(|| {
+        for document in &documents {
+            black_box(encoding.encode(document, AllowedSpecial::None));
+        }
+    });
+    let mut buffer = Vec::new();
+    let reused = time(|| {
+        for document in &documents {
+            buffer.clear();
+            encoding.encode_into(document, &mut buffer, AllowedSpecial::None);
+            black_box(&buffer);
+        }
+    });
+    println!("encode, fresh vector per document: {fresh:>10.2?}");
+    println!("encode_into, reused buffer:        {reused:>10.2?}");
+}
@@ -0,0 +1,66 @@
+//! Parses a completion of about 10k tokens, in one go and token by token
+//! with the streaming parser.
+//!
+//! Run with `cargo bench --bench parse_completion`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use openai_harmony::chat::{Message, Role};
+use openai_harmony::{load_harmony_encoding, HarmonyEncodingName, StreamableParser};
+
+const ITERATIONS: u32 = 50;
+const COMPLETION_TOKENS: usize = 10_000;
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let encoding = load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap();
+    let sentence = "The user asks about the weather, so check the forecast first. ";
+    let mut messages = Vec::new();
+    let mut tokens = Vec::new();
+    while tokens.len() < COMPLETION_TOKENS {
+        messages.push(
+            Message::from_role_and_content(Role::Assistant, sentence.repeat(20))
+                .with_channel("analysis"),
+        );
+        tokens = encoding.render_conversation(&messages, None).unwrap();
+    }
+    // the model output starts after the `<|start|>assistant` of the prompt
+    let prompt_suffix = encoding
+        .generation_prompt_tokens(Role::Assistant, None)
+        .unwrap();
+    let completion = tokens[prompt_suffix.len()..].to_vec();
+    println!(
+        "completion: {} tokens in {} messages",
+        completion.len(),
+        messages.len()
+    );
+
+    let parse = time(|| {
+        black_box(
+            encoding
+                .parse_messages_from_completion_tokens(
+                    completion.iter().copied(),
+                    Some(Role::Assistant),
+                )
+                .unwrap(),
+        );
+    });
+    let stream = time(|| {
+        let mut parser = StreamableParser::new(encoding.clone(), Some(Role::Assistant)).unwrap();
+        for &token in &completion {
+            parser.process(token).unwrap();
+            black_box(parser.last_content_delta());
+        }
+        black_box(parser.into_messages());
+    });
+    println!("parse_messages_from_completion_tokens: {parse:>10.2?}");
+    println!("StreamableParser, token by token:      {stream:>10.2?}");
+}
@@ -0,0 +1,86 @@
+//! Compares rendering a conversation from scratch with reusing a rendered
+//! static prefix, for a long developer prompt and a short growing tail.
+//!
+//! Run with `cargo bench --bench render_prefix`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use openai_harmony::chat::{DeveloperContent, Message, Role, SystemContent, ToolDescription};
+use openai_harmony::{load_harmony_encoding, HarmonyEncodingName};
+
+const ITERATIONS: u32 = 200;
+
+fn time(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let encoding = load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap();
+    let tools = (0..50)
+        .map(|i| {
+            ToolDescription::new(
+                format!("tool_{i}"),
+                format!("Tool number {i}. ").repeat(20),
+                Some(serde_json::json!({
+                    "type": "object",
+                    "properties": {"query": {"type": "string", "description": "What to look up."}},
+                    "required": ["query"],
+                })),
+            )
+        })
+        .collect();
+    let prefix_messages = vec![
+        Message::from_role_and_content(Role::System, SystemContent::new()),
+        Message::from_role_and_content(
+            Role::Developer,
+            DeveloperContent::new()
+                .with_instructions("Follow the house style guide. ".repeat(500))
+                .with_function_tools(tools),
+        ),
+    ];
+    let prefix = encoding.render_prefix(&prefix_messages).unwrap();
+    println!("prefix: {} tokens", prefix.tokens().len());
+
+    let mut tail = Vec::new();
+    for turn in 1..=4 {
+        tail.push(Message::from_role_and_content(
+            Role::User,
+            format!("Question {turn}?"),
+        ));
+        tail.push(
+            Message::from_role_and_content(Role::Assistant, format!("Answer {turn}."))
+                .with_channel("final"),
+        );
+        let full: Vec<Message> = prefix_messages.iter().chain(&tail).cloned().collect();
+
+        let from_scratch = time(|| {
+            black_box(
+                encoding
+                    .render_conversation_for_completion(&full, Role::Assistant, None)
+                    .unwrap(),
+            );
+        });
+        let with_prefix = time(|| {
+            black_box(
+                encoding
+                    .render_conversation_for_completion_with_prefix(
+                        &prefix,
+                        &tail,
+                        Role::Assistant,
+                        None,
+                    )
+                    .unwrap(),
+            );
+        });
+        println!(
+            "tail of {:>2} messages: from scratch {from_scratch:>10.2?}, with prefix {with_prefix:>10.2?} ({:.1}x)",
+            tail.len(),
+            from_scratch.as_secs_f64() / with_prefix.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Feed arbitrary token sequences to the parsers, which must return `Ok` or
+//! `Err` but never panic.
+//!
+//! Run with `cargo +nightly fuzz run parse_tokens` from the repository root.
+//! The encoding is loaded once through the usual vocab cache.
+
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use openai_harmony::{
+    chat::Role, load_harmony_encoding, HarmonyEncoding, HarmonyEncodingName, StreamableParser,
+};
+
+/// First id of the o200k_harmony special tokens and their count.
+const FIRST_SPECIAL: u32 = 199_998;
+const NUM_SPECIALS: u32 = 1091;
+
+fn encoding() -> &'static HarmonyEncoding {
+    static ENCODING: OnceLock<HarmonyEncoding> = OnceLock::new();
+    ENCODING.get_or_init(|| load_harmony_encoding(HarmonyEncodingName::HarmonyGptOss).unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, data)) = data.split_first() else {
+        return;
+    };
+    // Uniform random ids would almost never hit a special token, so every
+    // other id is drawn from the special range.
+    let tokens: Vec<u32> = data
+        .chunks_exact(2)
+        .map(|chunk| {
+            let raw = u16::from_le_bytes([chunk[0], chunk[1]]);
+            if raw & 1 == 0 {
+                FIRST_SPECIAL + u32::from(raw >> 1) % NUM_SPECIALS
+            } else {
+                u32::from(raw >> 1)
+            }
+        })
+        .collect();
+    let role = (selector & 1 == 1).then_some(Role::Assistant);
+
+    let encoding = encoding();
+    let _ = encoding.parse_messages_from_completion_tokens(tokens.iter().copied(), role.clone());
+    let _ = encoding
+        .parse_messages_from_completion_tokens_lossy(tokens.iter().copied(), role.clone());
+    let _ = encoding.parse_conversation_from_tokens(&tokens);
+
+    let Ok(mut parser) = StreamableParser::new(encoding.clone(), role) else {
+        return;
+    };
+    for &token in &tokens {
+        if parser.process(token).is_err() {
+            return;
+        }
+        let _ = parser.current_content();
+        let _ = parser.current_tool_call();
+        let _ = parser.state_json();
+    }
+    let _ = parser.process_eos();
+});
@@ -502,6 +502,21 @@ class HarmonyEncoding:
             config=config_dict,
         )
 
+    def count_conversation_tokens(
+        self,
+        conversation: Conversation,
+        config: Optional[RenderConversationConfig] = None,
+    ) -> int:
+        """Return the number of tokens ``render_conversation`` would produce."""
+        if config is None:
+            config_dict = {"auto_drop_analysis": True}
+        else:
+            config_dict = {"auto_drop_analysis": config.auto_drop_analysis}
+        return self._inner.count_conversation_tokens(
+            conversation_json=conversation.to_json(),
+            config=config_dict,
+        )
+
     def render(
         self, message: Message, render_options: Optional[RenderOptions] = None
     ) -> List[int]:
@@ -604,9 +619,11 @@ class HarmonyEncoding:
     # -- Stop tokens --------------------------------------------------
 
     def stop_tokens(self) -> List[int]:
+        """All message terminators: ``<|end|>``, ``<|return|>`` and ``<|call|>``."""
         return self._inner.stop_tokens()
 
     def stop_tokens_for_assistant_actions(self) -> List[int]:
+        """Stop tokens to use when sampling: ``<|return|>`` and ``<|call|>``."""
         return self._inner.stop_tokens_for_assistant_actions()
 
 
@@ -631,6 +648,11 @@ class StreamableParser:
         self._inner.process_eos()
         return self
 
+    def reset(self) -> "StreamableParser":
+        """Return to the initial state, reusing the parser's buffers."""
+        self._inner.reset()
+        return self
+
     @property
     def current_content(self) -> str:
         return self._inner.current_content
@@ -657,6 +679,11 @@ class StreamableParser:
     def tokens(self) -> List[int]:
         return self._inner.tokens
 
+    @property
+    def consumed_tokens(self) -> List[int]:
+        """Every token processed so far, e.g. to attach to a bug report."""
+        return self._inner.consumed_tokens
+
     @property
     def state_data(self) -> Dict[str, Any]:
         """Return a JSON string representing the parser's internal state."""
@@ -675,6 +702,11 @@ class StreamableParser:
     def current_channel(self) -> Optional[str]:
         return self._inner.current_channel
 
+    @property
+    def is_done(self) -> bool:
+        """Whether the last message is closed and no new header has started."""
+        return self._inner.is_done
+
 
 # Public helper --------------------------------------------------------------
 
@@ -3,8 +3,8 @@ use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer, Serialize,
 };
-use std::collections::BTreeMap;
-use std::{fmt::Display, marker::PhantomData};
+use std::collections::{BTreeMap, HashMap};
+use std::{fmt::Display, marker::PhantomData, str::FromStr};
 
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -70,6 +70,123 @@ impl Display for Role {
     }
 }
 
+/// Channel of a message, e.g. `analysis` for chain of thought.
+///
+/// Names other than the three standard channels are kept as
+/// [`Channel::Other`], so custom channels permitted by
+/// `RenderConversationConfig::allowed_channels` still round-trip. Matching
+/// on the standard variants surfaces a misspelt name such as `comentary`
+/// as `Other`. Serializes as the plain channel name.
+///
+/// Channels compare and hash by name, so an `Other` holding a standard name,
+/// e.g. `Channel::Other("final".into())`, equals the standard variant.
+#[derive(Clone, Debug)]
+pub enum Channel {
+    Analysis,
+    Commentary,
+    Final,
+    Other(String),
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Channel::Analysis => "analysis",
+            Channel::Commentary => "commentary",
+            Channel::Final => "final",
+            Channel::Other(name) => name,
+        }
+    }
+
+    /// Map a name to its channel without validating it, for names read from
+    /// a header or set through [`Message::with_channel`].
+    pub(crate) fn from_name(name: String) -> Self {
+        match name.as_str() {
+            "analysis" => Channel::Analysis,
+            "commentary" => Channel::Commentary,
+            "final" => Channel::Final,
+            _ => Channel::Other(name),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ParseChannelError {
+    #[error("channel name is empty")]
+    Empty,
+
+    /// The name contains whitespace or `<`, which end the channel in a
+    /// rendered header, so it would not parse back.
+    #[error("invalid channel name {0:?}")]
+    InvalidName(String),
+}
+
+impl FromStr for Channel {
+    type Err = ParseChannelError;
+
+    /// Parse a channel name, rejecting names that would not survive a
+    /// render and parse round trip.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if name.is_empty() {
+            return Err(ParseChannelError::Empty);
+        }
+        if name.contains(|c: char| c.is_whitespace() || c == '<') {
+            return Err(ParseChannelError::InvalidName(name.to_string()));
+        }
+        Ok(Self::from_name(name.to_string()))
+    }
+}
+
+impl TryFrom<&str> for Channel {
+    type Error = ParseChannelError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Channel> for String {
+    fn from(channel: Channel) -> Self {
+        match channel {
+            Channel::Other(name) => name,
+            known => known.as_str().to_string(),
+        }
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Channel {}
+
+impl std::hash::Hash for Channel {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state);
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Channel {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Accepts any string, like the `Option<String>` channel field it replaced,
+/// so existing JSON keeps deserializing.
+impl<'de> Deserialize<'de> for Channel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from_name)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum Content {
@@ -131,7 +248,7 @@ pub struct Message {
     /// channels, use `formatter.render_channel = False`. (note: parsing will raise an error
     /// if render_channel=False, but a channel was sampled).
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub channel: Option<String>,
+    pub channel: Option<Channel>,
 
     /// Content type of the message. This is typically only set by the model, you probably don't need to set this.
     pub content_type: Option<String>,
@@ -181,7 +298,7 @@ impl Message {
     where
         S: Into<String>,
     {
-        self.channel = Some(channel.into());
+        self.channel = Some(Channel::from_name(channel.into()));
         self
     }
     pub fn with_recipient<S>(mut self, recipient: S) -> Self
@@ -198,9 +315,222 @@ impl Message {
         self.content_type = Some(content_type.into());
         self
     }
+
+    /// Interpret this message as a tool call.
+    ///
+    /// Returns `Ok(None)` unless this is an assistant message addressed to a
+    /// `namespace.name` recipient such as `functions.get_weather` or
+    /// `browser.search`, split at the first `.`. The text content is parsed
+    /// as the JSON arguments of the call (typically sent with the
+    /// `<|constrain|>json` content type); empty content stands for no
+    /// arguments and yields an empty object.
+    pub fn as_tool_call(&self) -> Result<Option<ToolCall>, ToolCallError> {
+        if self.author.role != Role::Assistant {
+            return Ok(None);
+        }
+        let Some((namespace, name)) = self
+            .recipient
+            .as_deref()
+            .and_then(|recipient| recipient.split_once('.'))
+        else {
+            return Ok(None);
+        };
+        let mut arguments = String::new();
+        for content in &self.content {
+            match content {
+                Content::Text(text) => arguments.push_str(&text.text),
+                _ => return Err(ToolCallError::NonTextContent),
+            }
+        }
+        let arguments = if arguments.trim().is_empty() {
+            serde_json::Value::Object(Default::default())
+        } else {
+            serde_json::from_str(&arguments).map_err(|source| ToolCallError::InvalidArguments {
+                recipient: format!("{namespace}.{name}"),
+                source,
+            })?
+        };
+        Ok(Some(ToolCall {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            arguments,
+        }))
+    }
+}
+
+/// Characters of content shown by the [`Display`] impl of [`Message`].
+const DISPLAY_CONTENT_CHARS: usize = 80;
+
+/// A one-line summary for logs, e.g.
+/// `assistant[commentary→functions.get_weather]: {"city": "Paris"}`.
+///
+/// Text content is truncated to 80 characters with newlines escaped, and
+/// system and developer content are shown as `<system content>` and
+/// `<developer content>`.
+impl Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.author.role)?;
+        if let Some(name) = &self.author.name {
+            write!(f, ":{name}")?;
+        }
+        match (&self.channel, &self.recipient) {
+            (Some(channel), Some(recipient)) => write!(f, "[{channel}→{recipient}]")?,
+            (Some(channel), None) => write!(f, "[{channel}]")?,
+            (None, Some(recipient)) => write!(f, "[→{recipient}]")?,
+            (None, None) => {}
+        }
+        let content: String = self
+            .content
+            .iter()
+            .map(|content| match content {
+                Content::Text(TextContent { text }) => text.as_str(),
+                Content::SystemContent(_) => "<system content>",
+                Content::DeveloperContent(_) => "<developer content>",
+            })
+            .collect();
+        let mut chars = content.chars();
+        let shown: String = chars.by_ref().take(DISPLAY_CONTENT_CHARS).collect();
+        write!(f, ": {}", shown.replace('\n', "\\n"))?;
+        if chars.next().is_some() {
+            write!(f, "…")?;
+        }
+        Ok(())
+    }
+}
+
+/// A tool call extracted with [`Message::as_tool_call`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    pub namespace: String,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// A tool call whose arguments are still being streamed, see
+/// [`crate::StreamableParser::current_tool_call`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PartialToolCall {
+    pub namespace: String,
+    pub name: String,
+    /// Raw argument text received so far.
+    pub arguments: String,
+}
+
+impl PartialToolCall {
+    /// Parse the arguments received so far. Returns `None` while they do not
+    /// form a complete JSON value yet.
+    pub fn try_parse_arguments(&self) -> Option<serde_json::Value> {
+        serde_json::from_str(&self.arguments).ok()
+    }
+
+    /// The top-level fields of a JSON object whose values are complete in the
+    /// arguments received so far, e.g. `city` for `{"city": "SF", "unit": "c`.
+    ///
+    /// A string, object or array value is reported once it is closed, so a
+    /// nested value shows up all at once. Numbers, `true`, `false` and `null`
+    /// are reported once something follows them, since the next token may
+    /// still extend them. Scanning stops at the first malformed field, and
+    /// arguments that are not an object yield an empty map.
+    pub fn complete_fields(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut fields = serde_json::Map::new();
+        let text = self.arguments.as_bytes();
+        let skip_whitespace = |mut pos: usize| {
+            while text.get(pos).is_some_and(u8::is_ascii_whitespace) {
+                pos += 1;
+            }
+            pos
+        };
+        let mut pos = skip_whitespace(0);
+        if text.get(pos) != Some(&b'{') {
+            return fields;
+        }
+        loop {
+            pos = skip_whitespace(pos + 1);
+            let Some(key_end) = json_value_end(text, pos).filter(|_| text[pos] == b'"') else {
+                break;
+            };
+            let Ok(key) = serde_json::from_slice::<String>(&text[pos..key_end]) else {
+                break;
+            };
+            pos = skip_whitespace(key_end);
+            if text.get(pos) != Some(&b':') {
+                break;
+            }
+            let value_start = skip_whitespace(pos + 1);
+            let Some(value_end) = json_value_end(text, value_start) else {
+                break;
+            };
+            let Ok(value) = serde_json::from_slice(&text[value_start..value_end]) else {
+                break;
+            };
+            fields.insert(key, value);
+            pos = skip_whitespace(value_end);
+            if text.get(pos) != Some(&b',') {
+                break;
+            }
+        }
+        fields
+    }
+}
+
+/// End of the JSON value starting at `start`, or `None` if it is not
+/// complete yet. Only finds the boundary; the value is validated by parsing
+/// it afterwards.
+fn json_value_end(text: &[u8], start: usize) -> Option<usize> {
+    match text.get(start)? {
+        b'"' | b'{' | b'[' => {
+            let mut depth = 0usize;
+            let mut in_string = false;
+            let mut escaped = false;
+            for (offset, &byte) in text[start..].iter().enumerate() {
+                if in_string {
+                    match byte {
+                        _ if escaped => escaped = false,
+                        b'\\' => escaped = true,
+                        b'"' => in_string = false,
+                        _ => continue,
+                    }
+                    if in_string || depth > 0 {
+                        continue;
+                    }
+                    return Some(start + offset + 1);
+                }
+                match byte {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth = depth.checked_sub(1)?;
+                        if depth == 0 {
+                            return Some(start + offset + 1);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            None
+        }
+        _ => text[start..]
+            .iter()
+            .position(|byte| matches!(byte, b',' | b'}' | b']') || byte.is_ascii_whitespace())
+            .map(|len| start + len),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ToolCallError {
+    #[error("tool call content must be text")]
+    NonTextContent,
+
+    #[error("arguments of the call to {recipient} are not valid JSON: {source}")]
+    InvalidArguments {
+        recipient: String,
+        #[source]
+        source: serde_json::Error,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct TextContent {
     pub text: String,
 }
@@ -212,7 +542,37 @@ pub enum ReasoningEffort {
     High,
 }
 
+impl ReasoningEffort {
+    /// The name rendered on the `Reasoning:` line of the system message.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[error("unknown reasoning effort {0:?}, expected low, medium or high")]
+pub struct ParseReasoningEffortError(pub String);
+
+impl FromStr for ReasoningEffort {
+    type Err = ParseReasoningEffortError;
+
+    /// Parse a name as rendered by [`Self::as_str`]. Names are case-sensitive.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "low" => Ok(ReasoningEffort::Low),
+            "medium" => Ok(ReasoningEffort::Medium),
+            "high" => Ok(ReasoningEffort::High),
+            _ => Err(ParseReasoningEffortError(name.to_string())),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
 pub struct ChannelConfig {
     /// List of valid channels to instruct the model it can generate.
     ///
@@ -237,6 +597,7 @@ impl ChannelConfig {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ToolNamespaceConfig {
     pub name: String,
     pub description: Option<String>,
@@ -319,12 +680,15 @@ impl ToolNamespaceConfig {
 
 /// Content specific to system messages, includes model identity and its instructions
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct SystemContent {
     pub model_identity: Option<String>,
     pub reasoning_effort: Option<ReasoningEffort>,
     pub tools: Option<BTreeMap<String, ToolNamespaceConfig>>,
     /// Date/Time at which the conversation is taking place.
     /// Must be an isoformat date for portability to javascript.
+    /// The renderer never reads the system clock, so this is the only source
+    /// of the `Current date` line; it is omitted when `None`.
     pub conversation_start_date: Option<String>,
 
     /// The date at which the model's training data ends.
@@ -387,6 +751,11 @@ impl SystemContent {
         self.knowledge_cutoff = Some(knowledge_cutoff.into());
         self
     }
+    /// Leave the knowledge cutoff line out of the rendered system message.
+    pub fn without_knowledge_cutoff(mut self) -> Self {
+        self.knowledge_cutoff = None;
+        self
+    }
     pub fn with_channel_config(mut self, channel_config: ChannelConfig) -> Self {
         self.channel_config = Some(channel_config);
         self
@@ -409,9 +778,178 @@ impl SystemContent {
         self = self.with_tools(ToolNamespaceConfig::python());
         self
     }
+
+    /// Start building a system content from the defaults of [`Self::new`],
+    /// validating the configured dates on [`SystemContentBuilder::build`].
+    pub fn builder() -> SystemContentBuilder {
+        SystemContentBuilder {
+            content: Self::new(),
+        }
+    }
+
+    /// Apply settings from the environment, for quick experiments without
+    /// code changes. Opt-in: nothing reads these variables unless this is
+    /// called.
+    ///
+    /// `HARMONY_REASONING_EFFORT` (`low`, `medium` or `high`, any case)
+    /// replaces the reasoning effort and `HARMONY_MODEL_IDENTITY` the model
+    /// identity. A variable that is set and non-empty takes precedence over
+    /// the value already in `self`; unset or empty variables leave it as is.
+    pub fn with_env_overrides(self) -> Result<Self, SystemContentError> {
+        self.with_overrides_from(std::env::var_os)
+    }
+
+    pub(crate) fn with_overrides_from(
+        mut self,
+        lookup: impl Fn(&'static str) -> Option<std::ffi::OsString>,
+    ) -> Result<Self, SystemContentError> {
+        let var =
+            |name: &'static str, expected: &'static str| match lookup(name)
+                .filter(|value| !value.is_empty())
+            {
+                None => Ok(None),
+                Some(value) => value.into_string().map(Some).map_err(|value| {
+                    SystemContentError::InvalidEnvVar {
+                        name,
+                        value: value.to_string_lossy().into_owned(),
+                        expected,
+                    }
+                }),
+            };
+        const EFFORTS: &str = "low, medium or high";
+        if let Some(effort) = var("HARMONY_REASONING_EFFORT", EFFORTS)? {
+            let parsed = effort.to_ascii_lowercase().parse().map_err(|_| {
+                SystemContentError::InvalidEnvVar {
+                    name: "HARMONY_REASONING_EFFORT",
+                    value: effort,
+                    expected: EFFORTS,
+                }
+            })?;
+            self.reasoning_effort = Some(parsed);
+        }
+        if let Some(identity) = var("HARMONY_MODEL_IDENTITY", "UTF-8 text")? {
+            self.model_identity = Some(identity);
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum SystemContentError {
+    #[error("invalid {field} {value:?}: expected a date formatted as {expected}")]
+    InvalidDate {
+        field: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+
+    #[error("invalid environment variable {name}={value:?}: expected {expected}")]
+    InvalidEnvVar {
+        name: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+/// Validating builder for [`SystemContent`].
+///
+/// The reasoning effort is taken as a [`ReasoningEffort`], so it is always one
+/// of the allowed values; dates are checked when calling [`Self::build`].
+#[derive(Debug, Clone)]
+pub struct SystemContentBuilder {
+    content: SystemContent,
+}
+
+impl SystemContentBuilder {
+    pub fn model_identity(mut self, model_identity: impl Into<String>) -> Self {
+        self.content.model_identity = Some(model_identity.into());
+        self
+    }
+
+    pub fn reasoning_effort(mut self, effort: ReasoningEffort) -> Self {
+        self.content.reasoning_effort = Some(effort);
+        self
+    }
+
+    /// Must be formatted as `YYYY-MM-DD`.
+    pub fn conversation_start_date(mut self, conversation_start_date: impl Into<String>) -> Self {
+        self.content.conversation_start_date = Some(conversation_start_date.into());
+        self
+    }
+
+    /// Must be formatted as `YYYY-MM-DD`, or `YYYY-MM` like the default.
+    pub fn knowledge_cutoff(mut self, knowledge_cutoff: impl Into<String>) -> Self {
+        self.content.knowledge_cutoff = Some(knowledge_cutoff.into());
+        self
+    }
+
+    pub fn with_tools(mut self, ns_config: ToolNamespaceConfig) -> Self {
+        self.content = self.content.with_tools(ns_config);
+        self
+    }
+
+    pub fn build(self) -> Result<SystemContent, SystemContentError> {
+        if let Some(date) = &self.content.conversation_start_date {
+            if !is_valid_date(date, false) {
+                return Err(SystemContentError::InvalidDate {
+                    field: "conversation_start_date",
+                    value: date.clone(),
+                    expected: "YYYY-MM-DD",
+                });
+            }
+        }
+        if let Some(date) = &self.content.knowledge_cutoff {
+            if !is_valid_date(date, true) {
+                return Err(SystemContentError::InvalidDate {
+                    field: "knowledge_cutoff",
+                    value: date.clone(),
+                    expected: "YYYY-MM-DD or YYYY-MM",
+                });
+            }
+        }
+        Ok(self.content)
+    }
+}
+
+/// Check that `date` is a calendar date formatted as `YYYY-MM-DD`, or
+/// `YYYY-MM` if `allow_month` is set.
+fn is_valid_date(date: &str, allow_month: bool) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    let expected_lengths: &[usize] = match parts.len() {
+        2 if allow_month => &[4, 2],
+        3 => &[4, 2, 2],
+        _ => return false,
+    };
+    let well_formed = parts
+        .iter()
+        .zip(expected_lengths)
+        .all(|(part, len)| part.len() == *len && part.bytes().all(|b| b.is_ascii_digit()));
+    if !well_formed {
+        return false;
+    }
+    // SAFETY of the unwraps: every part consists of at most four ascii digits
+    let year: u32 = parts[0].parse().unwrap();
+    let month: u32 = parts[1].parse().unwrap();
+    if !(1..=12).contains(&month) {
+        return false;
+    }
+    let Some(day) = parts.get(2) else {
+        return true;
+    };
+    let day: u32 = day.parse().unwrap();
+    let is_leap_year =
+        year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    let days_in_month = match month {
+        2 if is_leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    (1..=days_in_month).contains(&day)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ToolDescription {
     pub name: String,
     pub description: String,
@@ -430,14 +968,56 @@ impl ToolDescription {
             parameters,
         }
     }
+
+    /// Check that the tool can be rendered and called.
+    ///
+    /// The name must be an identifier (ASCII letters, digits, `_` and `-`,
+    /// not starting with a digit or `-`) so it survives the round trip
+    /// through a `to=functions.{name}` recipient, the description must not
+    /// be empty, and `parameters`, if set, must be a JSON Schema object.
+    pub fn validate(&self) -> Result<(), ToolDescriptionError> {
+        let is_identifier = self
+            .name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_identifier {
+            return Err(ToolDescriptionError::InvalidName {
+                name: self.name.clone(),
+            });
+        }
+        let invalid = |field, expected| ToolDescriptionError::InvalidField {
+            name: self.name.clone(),
+            field,
+            expected,
+        };
+        if self.description.trim().is_empty() {
+            return Err(invalid("description", "a non-empty string"));
+        }
+        if self.parameters.as_ref().is_some_and(|p| !p.is_object()) {
+            return Err(invalid("parameters", "a JSON Schema object"));
+        }
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Conversation {
     pub messages: Vec<Message>,
 }
 
 impl Conversation {
+    /// An empty conversation, to be filled with the chainable helpers below,
+    /// e.g. `Conversation::new().system(SystemContent::new()).user("Hi")`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
     pub fn from_messages<I>(messages: I) -> Self
     where
         I: IntoIterator<Item = Message>,
@@ -446,6 +1026,525 @@ impl Conversation {
             messages: messages.into_iter().collect(),
         }
     }
+
+    /// Append a system message.
+    pub fn system(mut self, content: SystemContent) -> Self {
+        self.messages
+            .push(Message::from_role_and_content(Role::System, content));
+        self
+    }
+
+    /// Append a developer message.
+    pub fn developer(mut self, content: DeveloperContent) -> Self {
+        self.messages
+            .push(Message::from_role_and_content(Role::Developer, content));
+        self
+    }
+
+    /// Append a user message.
+    pub fn user(mut self, text: impl Into<String>) -> Self {
+        self.messages
+            .push(Message::from_role_and_content(Role::User, text.into()));
+        self
+    }
+
+    /// Append an assistant answer on the `final` channel.
+    pub fn assistant_final(mut self, text: impl Into<String>) -> Self {
+        self.messages.push(
+            Message::from_role_and_content(Role::Assistant, text.into()).with_channel("final"),
+        );
+        self
+    }
+
+    /// Append an assistant call to `{namespace}.{name}` with JSON
+    /// `arguments`, on the `commentary` channel like the model emits it.
+    pub fn tool_call(mut self, namespace: &str, name: &str, arguments: serde_json::Value) -> Self {
+        self.messages.push(
+            Message::from_role_and_content(Role::Assistant, arguments.to_string())
+                .with_channel("commentary")
+                .with_recipient(format!("{namespace}.{name}"))
+                .with_content_type("<|constrain|>json"),
+        );
+        self
+    }
+
+    /// Append the response of the tool `name`, the full recipient of the
+    /// call such as `functions.get_weather`, addressed to the assistant.
+    pub fn tool_result(mut self, name: impl Into<String>, content: impl Into<String>) -> Self {
+        self.messages.push(
+            Message::from_author_and_content(Author::new(Role::Tool, name), content.into())
+                .with_recipient("assistant")
+                .with_channel("commentary"),
+        );
+        self
+    }
+
+    /// Number of leading messages this conversation shares with `other`.
+    ///
+    /// Messages are compared with `==`, so author, recipient, channel,
+    /// content type and content all have to match. This is the part of a
+    /// cached prompt that can be reused, as long as the render does not
+    /// depend on later messages (see
+    /// [`crate::RenderConversationConfig::auto_drop_analysis`]).
+    pub fn common_prefix_len(&self, other: &Conversation) -> usize {
+        self.messages
+            .iter()
+            .zip(&other.messages)
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+
+    /// Split into everything up to and including the last user message, and
+    /// the messages after it, e.g. to regenerate the last response.
+    ///
+    /// The second half is empty when the conversation ends with a user
+    /// message. Without any user message the first half is empty.
+    pub fn split_at_last_user(&self) -> (Conversation, Conversation) {
+        let split = self
+            .messages
+            .iter()
+            .rposition(|message| message.author.role == Role::User)
+            .map_or(0, |index| index + 1);
+        let (head, tail) = self.messages.split_at(split);
+        (
+            Conversation::from_messages(head.to_vec()),
+            Conversation::from_messages(tail.to_vec()),
+        )
+    }
+
+    /// Convert an OpenAI chat-completions `messages` array.
+    ///
+    /// `system` and `developer` messages become developer instructions, since
+    /// the harmony system message carries model settings rather than
+    /// instructions. Assistant text goes to the `final` channel, or to
+    /// `commentary` when the message also has `tool_calls`; each tool call
+    /// becomes a `commentary` message to `functions.{name}` with the JSON
+    /// arguments as content. `tool` messages are matched to their call by
+    /// `tool_call_id` and become messages from `functions.{name}`. Content
+    /// may be a string, `null` or a list of `text` parts. An assistant
+    /// `reasoning_content` string becomes an `analysis` message in front.
+    pub fn from_openai_messages(
+        value: &serde_json::Value,
+    ) -> Result<Conversation, OpenAiMessagesError> {
+        let entries = value.as_array().ok_or(OpenAiMessagesError::NotAnArray)?;
+        let mut messages = Vec::new();
+        // tool_call_id -> function name, to attribute tool results
+        let mut call_names = HashMap::<&str, &str>::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let invalid = |field, expected| OpenAiMessagesError::InvalidField {
+                index,
+                field,
+                expected,
+            };
+            let role = entry
+                .get("role")
+                .and_then(serde_json::Value::as_str)
+                .ok_or_else(|| invalid("role", "a string"))?;
+            let text = openai_message_text(entry.get("content"))
+                .ok_or_else(|| invalid("content", "a string or a list of text parts"))?;
+            match role {
+                "system" | "developer" => messages.push(Message::from_role_and_content(
+                    Role::Developer,
+                    DeveloperContent::new().with_instructions(text),
+                )),
+                "user" => messages.push(Message::from_role_and_content(Role::User, text)),
+                "assistant" => {
+                    if let Some(reasoning) = entry
+                        .get("reasoning_content")
+                        .and_then(serde_json::Value::as_str)
+                        .filter(|reasoning| !reasoning.is_empty())
+                    {
+                        messages.push(
+                            Message::from_role_and_content(Role::Assistant, reasoning)
+                                .with_channel("analysis"),
+                        );
+                    }
+                    let tool_calls = match entry.get("tool_calls") {
+                        None | Some(serde_json::Value::Null) => &[][..],
+                        Some(calls) => calls
+                            .as_array()
+                            .ok_or_else(|| invalid("tool_calls", "a list"))?,
+                    };
+                    if !text.is_empty() {
+                        let channel = if tool_calls.is_empty() {
+                            "final"
+                        } else {
+                            "commentary"
+                        };
+                        messages.push(
+                            Message::from_role_and_content(Role::Assistant, text)
+                                .with_channel(channel),
+                        );
+                    }
+                    for call in tool_calls {
+                        let function = call.get("function");
+                        let name = function
+                            .and_then(|f| f.get("name"))
+                            .and_then(serde_json::Value::as_str)
+                            .ok_or_else(|| invalid("tool_calls", "calls with a function name"))?;
+                        let arguments = function
+                            .and_then(|f| f.get("arguments"))
+                            .and_then(serde_json::Value::as_str)
+                            .unwrap_or("{}");
+                        if let Some(id) = call.get("id").and_then(serde_json::Value::as_str) {
+                            call_names.insert(id, name);
+                        }
+                        messages.push(
+                            Message::from_role_and_content(Role::Assistant, arguments)
+                                .with_channel("commentary")
+                                .with_recipient(format!("functions.{name}"))
+                                .with_content_type("<|constrain|>json"),
+                        );
+                    }
+                }
+                "tool" => {
+                    let id = entry
+                        .get("tool_call_id")
+                        .and_then(serde_json::Value::as_str)
+                        .ok_or_else(|| invalid("tool_call_id", "a string"))?;
+                    let name = call_names.get(id).ok_or_else(|| {
+                        OpenAiMessagesError::UnknownToolCallId {
+                            index,
+                            id: id.to_string(),
+                        }
+                    })?;
+                    messages.push(
+                        Message::from_author_and_content(
+                            Author::new(Role::Tool, format!("functions.{name}")),
+                            text,
+                        )
+                        .with_recipient("assistant")
+                        .with_channel("commentary"),
+                    );
+                }
+                _ => {
+                    return Err(OpenAiMessagesError::UnknownRole {
+                        index,
+                        role: role.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(Conversation::from_messages(messages))
+    }
+
+    /// Convert to an OpenAI chat-completions `messages` array, the inverse
+    /// of [`Self::from_openai_messages`].
+    ///
+    /// Consecutive assistant messages are folded into one: `analysis` text
+    /// into `reasoning_content`, other text into `content` and tool calls
+    /// into `tool_calls`, with ids `call_0`, `call_1`, … that the following
+    /// tool messages refer to. Developer messages and text system messages
+    /// become `system` messages; a [`SystemContent`] has no OpenAI
+    /// counterpart and is left out, as are developer tools.
+    pub fn to_openai_messages(&self) -> serde_json::Value {
+        #[derive(Default)]
+        struct AssistantTurn {
+            content: Vec<String>,
+            reasoning: Vec<String>,
+            tool_calls: Vec<serde_json::Value>,
+        }
+        impl AssistantTurn {
+            fn into_json(self) -> serde_json::Value {
+                let mut turn = serde_json::json!({"role": "assistant"});
+                turn["content"] = if self.content.is_empty() {
+                    serde_json::Value::Null
+                } else {
+                    self.content.join("\n").into()
+                };
+                if !self.reasoning.is_empty() {
+                    turn["reasoning_content"] = self.reasoning.join("\n").into();
+                }
+                if !self.tool_calls.is_empty() {
+                    turn["tool_calls"] = self.tool_calls.into();
+                }
+                turn
+            }
+        }
+
+        let mut out = Vec::new();
+        let mut turn: Option<AssistantTurn> = None;
+        let mut call_count = 0;
+        // tool name -> ids of its calls that have not been answered yet
+        let mut pending_calls = HashMap::<String, std::collections::VecDeque<String>>::new();
+        for message in &self.messages {
+            if message.author.role != Role::Assistant {
+                out.extend(turn.take().map(AssistantTurn::into_json));
+            }
+            let text = openai_text_of(message);
+            match message.author.role {
+                Role::System | Role::Developer => {
+                    if !text.is_empty() {
+                        out.push(serde_json::json!({"role": "system", "content": text}));
+                    }
+                }
+                Role::User => out.push(serde_json::json!({"role": "user", "content": text})),
+                Role::Assistant => {
+                    let turn = turn.get_or_insert_with(Default::default);
+                    match message.recipient.as_deref().filter(|r| *r != "all") {
+                        Some(recipient) => {
+                            let id = format!("call_{call_count}");
+                            call_count += 1;
+                            pending_calls
+                                .entry(recipient.to_string())
+                                .or_default()
+                                .push_back(id.clone());
+                            let name = recipient.strip_prefix("functions.").unwrap_or(recipient);
+                            turn.tool_calls.push(serde_json::json!({
+                                "id": id,
+                                "type": "function",
+                                "function": {"name": name, "arguments": text},
+                            }));
+                        }
+                        None if message.channel == Some(Channel::Analysis) => {
+                            turn.reasoning.push(text)
+                        }
+                        None => turn.content.push(text),
+                    }
+                }
+                Role::Tool => {
+                    let tool = message.author.name.clone().unwrap_or_default();
+                    let id = pending_calls
+                        .get_mut(&tool)
+                        .and_then(|ids| ids.pop_front())
+                        .unwrap_or_else(|| {
+                            let id = format!("call_{call_count}");
+                            call_count += 1;
+                            id
+                        });
+                    out.push(serde_json::json!({
+                        "role": "tool",
+                        "tool_call_id": id,
+                        "content": text,
+                    }));
+                }
+            }
+        }
+        out.extend(turn.map(AssistantTurn::into_json));
+        serde_json::Value::Array(out)
+    }
+
+    /// Merge runs of consecutive messages from the same author into one,
+    /// joining their text with `\n`. See
+    /// [`Self::coalesce_adjacent_with_separator`].
+    pub fn coalesce_adjacent(&self) -> Conversation {
+        self.coalesce_adjacent_with_separator("\n")
+    }
+
+    /// Merge runs of consecutive messages that share author, channel and
+    /// content type, joining their text with `separator`.
+    ///
+    /// Only plain text messages without a recipient are merged: system and
+    /// developer content, tool calls and tool responses are kept as they are.
+    pub fn coalesce_adjacent_with_separator(&self, separator: &str) -> Conversation {
+        self.coalesce_with(separator, |previous, message| {
+            previous.recipient.is_none()
+                && message.recipient.is_none()
+                && message.author.role != Role::Tool
+                && previous.author == message.author
+                && previous.channel == message.channel
+                && previous.content_type == message.content_type
+        })
+    }
+
+    /// Merge runs of consecutive tool responses from the same tool into one
+    /// message, concatenating their text as is, e.g. for a tool that streams
+    /// its result in chunks.
+    ///
+    /// Responses are merged only if author, recipient, channel and content
+    /// type all match and every content is text; everything else, including
+    /// responses from a different tool, is kept as it is.
+    pub fn coalesce_tool_results(&self) -> Conversation {
+        self.coalesce_with("", |previous, message| {
+            message.author.role == Role::Tool
+                && previous.author == message.author
+                && previous.recipient == message.recipient
+                && previous.channel == message.channel
+                && previous.content_type == message.content_type
+        })
+    }
+
+    /// Merge each message into the one before it, joining their text with
+    /// `separator`, when `may_merge(previous, message)` holds and both
+    /// messages are text only.
+    fn coalesce_with(
+        &self,
+        separator: &str,
+        may_merge: impl Fn(&Message, &Message) -> bool,
+    ) -> Conversation {
+        fn text_of(message: &Message) -> Option<String> {
+            message
+                .content
+                .iter()
+                .map(|content| match content {
+                    Content::Text(TextContent { text }) => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        let mut messages: Vec<Message> = Vec::with_capacity(self.messages.len());
+        for message in &self.messages {
+            if let Some(previous) = messages.last_mut() {
+                if may_merge(previous, message) {
+                    if let (Some(previous_text), Some(text)) = (text_of(previous), text_of(message))
+                    {
+                        previous.content = vec![Content::from(previous_text + separator + &text)];
+                        continue;
+                    }
+                }
+            }
+            messages.push(message.clone());
+        }
+        Conversation { messages }
+    }
+
+    /// Check the conversation for structural mistakes that rendering does not
+    /// catch. This is advisory: invalid conversations still render.
+    ///
+    /// Checks that the system message, if any, comes first, that developer
+    /// messages come before the first user, assistant or tool message, and
+    /// that every tool response answers an earlier assistant call to that
+    /// tool. All problems are returned, in message order.
+    pub fn validate(&self) -> Result<(), Vec<ConversationError>> {
+        let mut errors = Vec::new();
+        let mut seen_system = false;
+        let mut seen_turn = false;
+        let mut pending_calls: Vec<&str> = Vec::new();
+        for (index, message) in self.messages.iter().enumerate() {
+            match message.author.role {
+                Role::System => {
+                    if seen_system {
+                        errors.push(ConversationError::MultipleSystemMessages { index });
+                    } else if index != 0 {
+                        errors.push(ConversationError::SystemMessageNotFirst { index });
+                    }
+                    seen_system = true;
+                }
+                Role::Developer => {
+                    if seen_turn {
+                        errors.push(ConversationError::DeveloperMessageAfterTurns { index });
+                    }
+                }
+                Role::User => seen_turn = true,
+                Role::Assistant => {
+                    seen_turn = true;
+                    match message.recipient.as_deref() {
+                        None | Some("all") | Some("user") | Some("assistant") => {}
+                        Some(recipient) => pending_calls.push(recipient),
+                    }
+                }
+                Role::Tool => {
+                    seen_turn = true;
+                    let Some(tool) = message.author.name.as_deref() else {
+                        errors.push(ConversationError::UnnamedToolResponse { index });
+                        continue;
+                    };
+                    match pending_calls.iter().position(|call| *call == tool) {
+                        Some(position) => {
+                            pending_calls.remove(position);
+                        }
+                        None => errors.push(ConversationError::ToolResponseWithoutCall {
+                            index,
+                            tool: tool.to_string(),
+                        }),
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Remove the chain of thought from parsed messages, keeping everything that
+/// is not on the `analysis` channel, such as `commentary` tool calls and
+/// `final` answers.
+///
+/// This is the read-side counterpart of
+/// [`RenderConversationConfig::auto_drop_analysis`](crate::RenderConversationConfig::auto_drop_analysis),
+/// except that every analysis message is dropped.
+pub fn drop_analysis(messages: Vec<Message>) -> Vec<Message> {
+    messages
+        .into_iter()
+        .filter(|message| message.channel != Some(Channel::Analysis))
+        .collect()
+}
+
+/// The last message on the `final` channel, e.g. the answer that counts when
+/// the model went back to analysis after a first final answer.
+///
+/// Parsing keeps messages in the order they were generated, so interleaved
+/// `analysis` and `final` messages stay separate; earlier final messages
+/// are still in `messages`.
+pub fn last_final_message(messages: &[Message]) -> Option<&Message> {
+    messages
+        .iter()
+        .rev()
+        .find(|message| message.channel == Some(Channel::Final))
+}
+
+/// Concatenate the text of messages per channel, e.g. to get all `analysis`
+/// and all `final` text of a parsed completion separately.
+///
+/// The text of each message is joined with `\n`, in message order. Tool calls
+/// and tool responses, i.e. messages with a recipient or from a tool, are left
+/// out, as are messages without a channel.
+pub fn collect_channels(messages: &[Message]) -> HashMap<String, String> {
+    let mut channels: HashMap<String, String> = HashMap::new();
+    for message in messages {
+        let Some(channel) = &message.channel else {
+            continue;
+        };
+        let is_tool_traffic = message.author.role == Role::Tool
+            || message.recipient.as_deref().is_some_and(|r| r != "all");
+        if is_tool_traffic {
+            continue;
+        }
+        let text: String = message
+            .content
+            .iter()
+            .filter_map(|content| match content {
+                Content::Text(TextContent { text }) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        channels
+            .entry(channel.to_string())
+            .and_modify(|collected| {
+                collected.push('\n');
+                collected.push_str(&text);
+            })
+            .or_insert(text);
+    }
+    channels
+}
+
+/// A problem reported by [`Conversation::validate`]. `index` is the position
+/// of the offending message.
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ConversationError {
+    #[error("message {index}: the system message must be the first message")]
+    SystemMessageNotFirst { index: usize },
+
+    #[error("message {index}: only one system message is allowed")]
+    MultipleSystemMessages { index: usize },
+
+    #[error(
+        "message {index}: developer messages must come before user, assistant and tool messages"
+    )]
+    DeveloperMessageAfterTurns { index: usize },
+
+    #[error(
+        "message {index}: tool response has no author name, so it cannot be matched to a call"
+    )]
+    UnnamedToolResponse { index: usize },
+
+    #[error("message {index}: response from {tool} does not follow an assistant call to {tool}")]
+    ToolResponseWithoutCall { index: usize, tool: String },
 }
 
 impl<'a> IntoIterator for &'a Conversation {
@@ -504,6 +1603,7 @@ where
 
 /// Content specific to developer messages, includes developer identity and its instructions
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
 pub struct DeveloperContent {
     pub instructions: Option<String>,
     pub tools: Option<BTreeMap<String, ToolNamespaceConfig>>,
@@ -532,4 +1632,117 @@ impl DeveloperContent {
         self = self.with_tools(ToolNamespaceConfig::new("functions", None, tools));
         self
     }
+
+    /// Developer content with `functions` tools built from JSON objects of
+    /// the form `{"name": ..., "description": ..., "parameters": ...}`, e.g.
+    /// as listed by an MCP server. `parameters` is optional and must be a
+    /// JSON Schema object. Every tool is checked with
+    /// [`ToolDescription::validate`].
+    pub fn with_tools_from_json(tools: &[serde_json::Value]) -> Result<Self, ToolDescriptionError> {
+        let tools = tools
+            .iter()
+            .enumerate()
+            .map(|(index, tool)| tool_description_from_json(index, tool))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new().with_function_tools(tools))
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ToolDescriptionError {
+    #[error("tool at index {index} has no name")]
+    MissingName { index: usize },
+
+    #[error("tool name {name:?} is not a valid identifier")]
+    InvalidName { name: String },
+
+    #[error("tool {name}: `{field}` must be {expected}")]
+    InvalidField {
+        name: String,
+        field: &'static str,
+        expected: &'static str,
+    },
+}
+
+fn tool_description_from_json(
+    index: usize,
+    tool: &serde_json::Value,
+) -> Result<ToolDescription, ToolDescriptionError> {
+    let name = tool
+        .get("name")
+        .and_then(serde_json::Value::as_str)
+        .filter(|name| !name.is_empty())
+        .ok_or(ToolDescriptionError::MissingName { index })?;
+    let invalid = |field, expected| ToolDescriptionError::InvalidField {
+        name: name.to_string(),
+        field,
+        expected,
+    };
+    let description = match tool.get("description") {
+        None | Some(serde_json::Value::Null) => "",
+        Some(description) => description
+            .as_str()
+            .ok_or_else(|| invalid("description", "a string"))?,
+    };
+    let parameters = match tool.get("parameters") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(parameters) if parameters.is_object() => Some(parameters.clone()),
+        Some(_) => return Err(invalid("parameters", "a JSON Schema object")),
+    };
+    let tool = ToolDescription::new(name, description, parameters);
+    tool.validate()?;
+    Ok(tool)
+}
+
+/// Error returned by [`Conversation::from_openai_messages`].
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum OpenAiMessagesError {
+    #[error("expected a JSON array of messages")]
+    NotAnArray,
+
+    #[error("message {index}: unknown role {role:?}")]
+    UnknownRole { index: usize, role: String },
+
+    #[error("message {index}: `{field}` must be {expected}")]
+    InvalidField {
+        index: usize,
+        field: &'static str,
+        expected: &'static str,
+    },
+
+    #[error("message {index}: no earlier tool call has id {id:?}")]
+    UnknownToolCallId { index: usize, id: String },
+}
+
+/// Text a message contributes to an OpenAI message: its text parts, or the
+/// instructions of developer content.
+fn openai_text_of(message: &Message) -> String {
+    message
+        .content
+        .iter()
+        .filter_map(|content| match content {
+            Content::Text(TextContent { text }) => Some(text.as_str()),
+            Content::DeveloperContent(developer) => developer.instructions.as_deref(),
+            Content::SystemContent(_) => None,
+        })
+        .collect()
+}
+
+/// Text of an OpenAI message `content`: a string, `null` or a list of parts
+/// of which only `text` parts are supported.
+fn openai_message_text(content: Option<&serde_json::Value>) -> Option<String> {
+    match content {
+        None | Some(serde_json::Value::Null) => Some(String::new()),
+        Some(serde_json::Value::String(text)) => Some(text.clone()),
+        Some(serde_json::Value::Array(parts)) => parts
+            .iter()
+            .map(
+                |part| match part.get("type").and_then(serde_json::Value::as_str) {
+                    Some("text") => part.get("text").and_then(serde_json::Value::as_str),
+                    _ => None,
+                },
+            )
+            .collect(),
+        Some(_) => None,
+    }
 }
@@ -1,5 +1,8 @@
 use crate::{
-    chat::{Author, Content, Message, ReasoningEffort, Role, SystemContent, TextContent},
+    chat::{
+        Author, Channel, Content, Conversation, Message, ParseReasoningEffortError,
+        PartialToolCall, Role, SystemContent, TextContent,
+    },
     tiktoken::{CoreBPE, Rank},
 };
 use anyhow::Context as _;
@@ -18,6 +21,107 @@ pub struct ParsedHeader {
     content_type: Option<String>,
 }
 
+/// Best-effort view of a message that was cut off before its terminating
+/// token, e.g. because generation hit `max_new_tokens`.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PartialMessage {
+    /// Author, if the role could be determined from the header read so far.
+    pub author: Option<Author>,
+    pub recipient: Option<String>,
+    pub channel: Option<String>,
+    pub content_type: Option<String>,
+    /// Decoded UTF-8 prefix of the content. Empty if the header never completed.
+    pub content: String,
+}
+
+/// The token that ended a parsed message, as reported by
+/// [`StreamableParser::terminators`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Terminator {
+    /// `<|end|>`: the message is done, but the turn may continue.
+    End,
+    /// `<|return|>`: the final answer, the model is done.
+    Return,
+    /// `<|call|>`: a tool call, the model waits for the tool's response.
+    Call,
+}
+
+/// The tokens that end a message, as returned by
+/// [`HarmonyEncoding::assistant_stop_config`], for configuring a sampler
+/// once at startup.
+///
+/// Sample with [`Self::sampling_stop_tokens`] as the stop tokens: `<|end|>`
+/// also ends the analysis and commentary messages within an assistant turn,
+/// so stopping on it cuts the model off before its final answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct StopConfig {
+    /// Id of `<|end|>`, which ends a message but not the turn.
+    pub end: Rank,
+    /// Id of `<|return|>`, which ends the final answer and the turn.
+    #[serde(rename = "return")]
+    pub return_: Rank,
+    /// Id of `<|call|>`, which ends a tool call; the turn continues once the
+    /// tool's response has been appended.
+    pub call: Rank,
+}
+
+impl StopConfig {
+    /// The tokens to stop sampling an assistant turn on, `<|call|>` and
+    /// `<|return|>`, in that order.
+    pub fn sampling_stop_tokens(&self) -> [Rank; 2] {
+        [self.call, self.return_]
+    }
+
+    /// All three terminators, e.g. for splitting rendered conversations.
+    pub fn all_tokens(&self) -> [Rank; 3] {
+        [self.end, self.return_, self.call]
+    }
+
+    /// What `token` means as the last sampled token, or `None` if it is not
+    /// a terminator.
+    pub fn terminator(&self, token: Rank) -> Option<Terminator> {
+        match token {
+            t if t == self.end => Some(Terminator::End),
+            t if t == self.return_ => Some(Terminator::Return),
+            t if t == self.call => Some(Terminator::Call),
+            _ => None,
+        }
+    }
+}
+
+/// Error returned by [`HarmonyEncoding::decode_strict`]. Positions are
+/// indices into the decoded tokens.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("token {token} at position {position} is not in the vocabulary")]
+    InvalidToken { position: usize, token: Rank },
+
+    #[error("token {token} at position {position} starts an invalid UTF-8 sequence")]
+    InvalidUtf8 { position: usize, token: Rank },
+
+    #[error("tokens end in the middle of a UTF-8 character started by token {token} at position {position}")]
+    TruncatedUtf8 { position: usize, token: Rank },
+}
+
+/// Error returned by [`HarmonyEncoding::encode_with_limit`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    #[error("text encodes to more than {max_tokens} tokens")]
+    LimitExceeded { max_tokens: usize },
+}
+
+/// Which special tokens [`HarmonyEncoding::encode`] recognizes in text.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum AllowedSpecial {
+    /// Every special token of the tokenizer, e.g. `<|start|>` or `<|end|>`.
+    All,
+    /// No special tokens: `<|...|>` sequences are encoded as ordinary text.
+    #[default]
+    None,
+    /// Only the listed special tokens.
+    Set(HashSet<String>),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum RenderFormattingTokenError {
     #[error("tried to render unmapped formatting token {0}")]
@@ -32,6 +136,79 @@ pub(crate) enum RenderFormattingTokenError {
     },
 }
 
+/// Error returned by [`HarmonyEncoding::parse_system_content`].
+#[derive(thiserror::Error, Debug)]
+pub enum ParseSystemContentError {
+    #[error("could not parse tokens into messages")]
+    InvalidTokens(#[source] anyhow::Error),
+
+    #[error("no complete system message found")]
+    MissingSystemMessage,
+
+    #[error("unknown reasoning effort {0:?}")]
+    InvalidReasoningEffort(String),
+
+    #[error("unrecognized segment at byte {offset} of the system message: {segment:?}")]
+    UnrecognizedSegment { segment: String, offset: usize },
+}
+
+/// Why a token sequence could not be parsed into messages.
+///
+/// The parsing methods of [`HarmonyEncoding`] and [`StreamableParser`] return
+/// this type, so callers can match on the failure, e.g. retry a
+/// [`ParseError::TruncatedMessage`] with more tokens. Only the methods that
+/// take [`ParseOptions`] return `anyhow::Result`, because they also reject
+/// invalid options; their parse failures still downcast to this type.
+/// `position` is the index of the offending token among the tokens processed
+/// so far.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ParseError {
+    #[error("unexpected token {token} at position {position} while expecting <|start|>")]
+    MissingMessageDelimiter { position: usize, token: Rank },
+
+    #[error("unexpected token {token} at position {position} in a message header")]
+    UnexpectedTokenInHeader { position: usize, token: Rank },
+
+    #[error("token {token} at position {position} is not in the vocabulary")]
+    InvalidToken { position: usize, token: Rank },
+
+    /// The tokens ended inside a message header, e.g. because generation
+    /// was cut off. A message cut off inside its content is ended by the end
+    /// of the input instead; [`HarmonyEncoding::parse_messages_from_completion_tokens_lossy`]
+    /// reports it as a [`PartialMessage`].
+    #[error("the tokens ended before the message header was complete")]
+    TruncatedMessage,
+
+    #[error("message header is not valid UTF-8")]
+    InvalidHeaderEncoding,
+
+    /// The content contains bytes that are not valid UTF-8, including a
+    /// multi-byte character that the message ends in the middle of.
+    #[error("message content is not valid UTF-8")]
+    InvalidContentEncoding,
+
+    #[error("message header did not contain a role")]
+    MissingRole,
+
+    #[error("unknown role {0:?}")]
+    UnknownRole(String),
+
+    #[error("channel marker present but no channel value found in header")]
+    EmptyChannel,
+
+    /// The header names a channel outside [`ParseOptions::allowed_channels`].
+    #[error("unknown channel {0:?}")]
+    UnknownChannel(String),
+
+    /// The `to=` recipient is empty or has an empty dot-separated part, e.g.
+    /// `functions.`.
+    #[error("invalid recipient {0:?}")]
+    InvalidToolRecipient(String),
+
+    #[error("unexpected tokens remaining in message header: {0:?}")]
+    UnexpectedHeaderContent(Vec<String>),
+}
+
 /// These are formatting tokens that the renderer can use to generically
 /// format the output of the model, but at formatting time, they are replaced
 /// by actual tokens from the tokenizers vocabulary.
@@ -89,6 +266,29 @@ pub struct HarmonyEncoding {
     pub(crate) format_token_mapping: HashMap<FormattingToken, String>,
     pub(crate) stop_formatting_tokens: HashSet<FormattingToken>,
     pub(crate) stop_formatting_tokens_for_assistant_actions: HashSet<FormattingToken>,
+    /// Special tokens registered on top of the tokenizer's own, see
+    /// [`HarmonyEncoding::with_additional_special_tokens`].
+    pub(crate) additional_special_tokens: Vec<String>,
+    /// Ids of the formatting tokens, resolved once when the encoding is built.
+    pub(crate) token_ids: FormattingTokenIds,
+}
+
+/// Ids of the formatting tokens exposed through accessors like
+/// [`HarmonyEncoding::start_token`].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FormattingTokenIds {
+    start: Rank,
+    end: Rank,
+    message: Rank,
+    channel: Rank,
+    call: Rank,
+    return_: Rank,
+    constrain: Rank,
+    /// Ids of the stop formatting tokens, in ascending order.
+    stop: Vec<Rank>,
+    /// Ids of the stop formatting tokens for assistant actions, in ascending
+    /// order.
+    stop_for_assistant_actions: Vec<Rank>,
 }
 
 impl std::fmt::Debug for HarmonyEncoding {
@@ -127,6 +327,274 @@ impl HarmonyEncoding {
         &self.tokenizer
     }
 
+    /// Look up the ids backing [`FormattingTokenIds`]. Fails if one of the
+    /// formatting tokens is not mapped to a single token.
+    pub(crate) fn resolve_formatting_token_ids(&self) -> anyhow::Result<FormattingTokenIds> {
+        let mut stop: Vec<Rank> = self.stop_tokens()?.into_iter().collect();
+        stop.sort_unstable();
+        let mut stop_for_assistant_actions: Vec<Rank> = self
+            .stop_tokens_for_assistant_actions()?
+            .into_iter()
+            .collect();
+        stop_for_assistant_actions.sort_unstable();
+        Ok(FormattingTokenIds {
+            start: self.render_formatting_token(FormattingToken::Start)?,
+            end: self.render_formatting_token(FormattingToken::EndMessage)?,
+            message: self.render_formatting_token(FormattingToken::Message)?,
+            channel: self.render_formatting_token(FormattingToken::Channel)?,
+            call: self.render_formatting_token(FormattingToken::EndMessageAssistantToTool)?,
+            return_: self.render_formatting_token(FormattingToken::EndMessageDoneSampling)?,
+            constrain: self.render_formatting_token(FormattingToken::ConstrainedFormat)?,
+            stop,
+            stop_for_assistant_actions,
+        })
+    }
+
+    /// The regex the tokenizer splits text with before applying BPE.
+    pub fn pattern(&self) -> &str {
+        self.tokenizer.pattern()
+    }
+
+    /// Number of distinct token ids, special tokens included. For
+    /// `o200k_harmony` loaded from the expected vocab file this is
+    /// `201_089`, so a different value means the wrong `.tiktoken` file was
+    /// loaded.
+    pub fn vocab_size(&self) -> usize {
+        self.tokenizer.vocab_size()
+    }
+
+    /// Short hex fingerprint of the vocabulary, special tokens and split
+    /// pattern, e.g. for a model server and its clients to check at startup
+    /// that they loaded the same vocab. Equal for encodings that tokenize
+    /// identically, different as soon as one token or rank differs.
+    /// Computing it walks the whole vocabulary.
+    pub fn fingerprint(&self) -> String {
+        let mut fingerprint = self.tokenizer.fingerprint();
+        fingerprint.truncate(16);
+        fingerprint
+    }
+
+    /// Whether `token` is one of the tokenizer's special tokens, formatting
+    /// tokens and reserved placeholders included. This is a hash lookup, no
+    /// decoding involved.
+    pub fn is_special_token(&self, token: Rank) -> bool {
+        self.tokenizer.is_special_token(token)
+    }
+
+    /// Tokenize arbitrary text, encoding the special tokens selected by
+    /// `allowed_special` as such, like tiktoken's `encode`.
+    ///
+    /// Unlike tiktoken, special tokens that are not allowed are not an error:
+    /// they are encoded as ordinary text.
+    pub fn encode(&self, text: &str, allowed_special: AllowedSpecial) -> Vec<Rank> {
+        let mut out = Vec::new();
+        self.encode_into(text, &mut out, allowed_special);
+        out
+    }
+
+    /// Show how `text` is split into tokens: the bytes and rank of every
+    /// token, in order, as [`Self::encode`] with [`AllowedSpecial::None`]
+    /// produces them. Meant for debugging unexpected token counts.
+    pub fn explain_encoding(&self, text: &str) -> Vec<(Vec<u8>, Rank)> {
+        self.tokenizer
+            .encode_ordinary(text)
+            .into_iter()
+            .map(|token| {
+                let bytes = self
+                    .tokenizer
+                    .decode_bytes([token])
+                    .expect("encoded tokens are in the vocabulary");
+                (bytes, token)
+            })
+            .collect()
+    }
+
+    /// Tokenize untrusted text, giving up once it would take more than
+    /// `max_tokens` tokens instead of encoding all of it.
+    ///
+    /// Special tokens are not recognized, so `<|...|>` sequences in the text
+    /// are encoded as ordinary text. A pre-tokenizer piece that cannot fit in
+    /// the remaining budget, e.g. a single very long word, is rejected before
+    /// it is encoded.
+    pub fn encode_with_limit(
+        &self,
+        text: &str,
+        max_tokens: usize,
+    ) -> Result<Vec<Rank>, EncodeError> {
+        self.tokenizer
+            .encode_ordinary_with_limit(text, max_tokens)
+            .ok_or(EncodeError::LimitExceeded { max_tokens })
+    }
+
+    /// Like [`Self::encode`], but appends the tokens to `out`, so one buffer
+    /// can be reused across many documents.
+    pub fn encode_into(&self, text: &str, out: &mut Vec<Rank>, allowed_special: AllowedSpecial) {
+        match allowed_special {
+            AllowedSpecial::All => {
+                let allowed = self.tokenizer.special_tokens();
+                self.tokenizer.encode_into(text, &allowed, out);
+            }
+            AllowedSpecial::None => self.tokenizer.encode_ordinary_into(text, out),
+            AllowedSpecial::Set(tokens) => {
+                let allowed = tokens.iter().map(String::as_str).collect();
+                self.tokenizer.encode_into(text, &allowed, out);
+            }
+        }
+    }
+
+    /// Id of `<|start|>`.
+    pub fn start_token(&self) -> Rank {
+        self.token_ids.start
+    }
+
+    /// Id of `<|end|>`.
+    pub fn end_token(&self) -> Rank {
+        self.token_ids.end
+    }
+
+    /// Id of `<|message|>`.
+    pub fn message_token(&self) -> Rank {
+        self.token_ids.message
+    }
+
+    /// Id of `<|channel|>`.
+    pub fn channel_token(&self) -> Rank {
+        self.token_ids.channel
+    }
+
+    /// Id of `<|call|>`.
+    pub fn call_token(&self) -> Rank {
+        self.token_ids.call
+    }
+
+    /// Id of `<|return|>`.
+    pub fn return_token(&self) -> Rank {
+        self.token_ids.return_
+    }
+
+    /// Id of `<|constrain|>`.
+    pub fn constrain_token(&self) -> Rank {
+        self.token_ids.constrain
+    }
+
+    /// The ids of [`Self::stop_tokens`] as a sorted slice, e.g. to pass as EOS
+    /// tokens to a model server.
+    pub fn stop_token_ids(&self) -> &[Rank] {
+        &self.token_ids.stop
+    }
+
+    /// The ids of [`Self::stop_tokens_for_assistant_actions`] as a sorted
+    /// slice.
+    pub fn stop_token_ids_for_assistant_actions(&self) -> &[Rank] {
+        &self.token_ids.stop_for_assistant_actions
+    }
+
+    /// The ids of `<|end|>`, `<|return|>` and `<|call|>` with their meaning,
+    /// bundled for a model server adapter. See [`StopConfig`].
+    pub fn assistant_stop_config(&self) -> StopConfig {
+        StopConfig {
+            end: self.token_ids.end,
+            return_: self.token_ids.return_,
+            call: self.token_ids.call,
+        }
+    }
+
+    /// Turn tokens into a human readable string for logs.
+    ///
+    /// Regular tokens are decoded as text (invalid UTF-8 is replaced with
+    /// U+FFFD), special tokens are shown with their literal spelling such as
+    /// `<|channel|>`, and ids the tokenizer does not know become
+    /// `<|unknown_{id}|>`.
+    pub fn render_tokens_for_debug(&self, tokens: &[Rank]) -> String {
+        let mut out = String::new();
+        let mut text_bytes = Vec::new();
+        for &token in tokens {
+            match self.tokenizer.decode_bytes([token]) {
+                Ok(bytes) if !self.tokenizer.is_special_token(token) => text_bytes.extend(bytes),
+                decoded => {
+                    out.push_str(&String::from_utf8_lossy(&text_bytes));
+                    text_bytes.clear();
+                    match decoded {
+                        Ok(bytes) => out.push_str(&String::from_utf8_lossy(&bytes)),
+                        Err(_) => out.push_str(&format!("<|unknown_{token}|>")),
+                    }
+                }
+            }
+        }
+        out.push_str(&String::from_utf8_lossy(&text_bytes));
+        out
+    }
+
+    /// Decode tokens as UTF-8, leaving out an incomplete multi-byte sequence
+    /// at the end. Returns the text together with the number of trailing
+    /// bytes that were left out, so a streaming consumer can hold back the
+    /// tokens they came from and decode them again with the next chunk.
+    /// Invalid sequences elsewhere are replaced with U+FFFD.
+    pub fn decode_utf8_lossy_tracking(&self, tokens: &[Rank]) -> anyhow::Result<(String, usize)> {
+        let mut bytes = self.tokenizer.decode_bytes(tokens.iter().copied())?;
+        let text = drain_complete_utf8(&mut bytes);
+        Ok((text, bytes.len()))
+    }
+
+    /// Decode tokens as UTF-8, failing instead of inserting U+FFFD.
+    ///
+    /// The error names the token the offending bytes come from, and tells a
+    /// sequence that is cut off at the end ([`DecodeError::TruncatedUtf8`])
+    /// from one that is invalid ([`DecodeError::InvalidUtf8`]).
+    pub fn decode_strict(&self, tokens: &[Rank]) -> Result<String, DecodeError> {
+        let mut bytes = Vec::new();
+        // byte offset at which each token's bytes end
+        let mut token_ends = Vec::with_capacity(tokens.len());
+        for (position, &token) in tokens.iter().enumerate() {
+            let token_bytes = self
+                .tokenizer
+                .decode_bytes([token])
+                .map_err(|_| DecodeError::InvalidToken { position, token })?;
+            bytes.extend(token_bytes);
+            token_ends.push(bytes.len());
+        }
+        String::from_utf8(bytes).map_err(|e| {
+            let error = e.utf8_error();
+            let position = token_ends.partition_point(|&end| end <= error.valid_up_to());
+            let token = tokens[position];
+            match error.error_len() {
+                Some(_) => DecodeError::InvalidUtf8 { position, token },
+                None => DecodeError::TruncatedUtf8 { position, token },
+            }
+        })
+    }
+
+    /// Register extra special tokens, e.g. control tokens a fine-tune
+    /// introduced on top of the reserved ids.
+    ///
+    /// The new tokens are encoded atomically and, when they appear in a
+    /// message header, split off the same way `<|constrain|>` is. For
+    /// `o200k_harmony` the ids that are safe to claim are the placeholders
+    /// `<|reserved_200000|>`, `<|reserved_200001|>`, `<|reserved_200004|>`,
+    /// `<|reserved_200009|>` through `<|reserved_200011|>`,
+    /// `<|reserved_200013|>` and the range `200014..=201088`. Claiming any
+    /// other id that is already in use, or a name that is already
+    /// registered, is an error.
+    pub fn with_additional_special_tokens(
+        mut self,
+        tokens: impl IntoIterator<Item = (String, u32)>,
+    ) -> anyhow::Result<Self> {
+        let tokens: Vec<(String, Rank)> = tokens.into_iter().collect();
+        let tokenizer = self
+            .tokenizer
+            .with_additional_special_tokens(tokens.iter().cloned())
+            .map_err(|e| anyhow::anyhow!(e))
+            .context("could not register additional special tokens")?;
+        self.tokenizer = Arc::new(tokenizer);
+        self.additional_special_tokens
+            .extend(tokens.into_iter().map(|(token, _)| token));
+        Ok(self)
+    }
+
+    /// Every token that terminates a message: `<|end|>` ends a message that is
+    /// followed by another one, `<|call|>` ends an assistant tool call and
+    /// `<|return|>` ends the final assistant message. Use this set when
+    /// parsing whole conversations.
     pub fn stop_tokens(&self) -> anyhow::Result<HashSet<Rank>> {
         self.stop_formatting_tokens
             .iter()
@@ -141,6 +609,10 @@ impl HarmonyEncoding {
             .collect()
     }
 
+    /// The tokens that end an assistant's turn: `<|call|>` after a tool call and
+    /// `<|return|>` after the final answer. Use this set as the stop tokens
+    /// when sampling, so the model keeps going through `<|end|>` between its
+    /// analysis and final messages.
     pub fn stop_tokens_for_assistant_actions(&self) -> anyhow::Result<HashSet<Rank>> {
         self.stop_formatting_tokens_for_assistant_actions
             .iter()
@@ -168,8 +640,74 @@ impl HarmonyEncoding {
     where
         I: IntoIterator<Item = &'a Message>,
         B: Extend<Rank>,
+    {
+        let (messages, render_options) = Self::plan_conversation_render(conversation, config)?;
+        let suffix = Self::extra_system_suffix(config);
+        messages
+            .into_iter()
+            .try_for_each(|msg| self.render_message_into(msg, into, Some(&render_options), suffix))
+    }
+
+    /// Lazily render a conversation, producing the same tokens as
+    /// [`Self::render_conversation`] one message at a time.
+    ///
+    /// Iteration stops after the first error.
+    pub fn render_conversation_iter<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+        config: &'a RenderConversationConfig,
+    ) -> impl Iterator<Item = anyhow::Result<Rank>> + 'a {
+        let (messages, render_options, plan_error) =
+            match Self::plan_conversation_render(&conversation.messages, Some(config)) {
+                Ok((messages, render_options)) => (messages, render_options, None),
+                Err(e) => (Vec::new(), RenderOptions::default(), Some(e)),
+            };
+        plan_error
+            .map(Err)
+            .into_iter()
+            .chain(messages.into_iter().flat_map(move |msg| {
+                let mut tokens = Vec::new();
+                let suffix = Self::extra_system_suffix(Some(config));
+                let error = self
+                    .render_message_into(msg, &mut tokens, Some(&render_options), suffix)
+                    .err();
+                if error.is_some() {
+                    tokens.clear();
+                }
+                tokens.into_iter().map(Ok).chain(error.map(Err))
+            }))
+            .scan(false, |failed, item| {
+                if *failed {
+                    return None;
+                }
+                *failed = item.is_err();
+                Some(item)
+            })
+    }
+
+    /// Select the messages of a conversation that get rendered, dropping
+    /// analysis messages as requested by `config`, along with the render
+    /// options that apply to all of them. Fails if a message uses a channel
+    /// that `config` does not allow.
+    fn plan_conversation_render<'a, I>(
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<&'a Message>, RenderOptions)>
+    where
+        I: IntoIterator<Item = &'a Message>,
     {
         let messages: Vec<_> = conversation.into_iter().collect();
+        if let Some(allowed) = config.and_then(|c| c.allowed_channels.as_ref()) {
+            for channel in messages
+                .iter()
+                .filter_map(|msg| msg.channel.as_ref().map(Channel::as_str))
+            {
+                anyhow::ensure!(
+                    allowed.iter().any(|c| c == channel),
+                    "channel {channel:?} is not one of the allowed channels {allowed:?}"
+                );
+            }
+        }
         let has_function_tools = messages.iter().any(|msg| {
             msg.content.iter().any(|c| {
                 if let Content::DeveloperContent(dev) = c {
@@ -187,15 +725,17 @@ impl HarmonyEncoding {
                 }
             })
         });
+        let suppress_tools = config.is_some_and(|c| c.suppress_tools);
         let render_options = RenderOptions {
-            conversation_has_function_tools: has_function_tools,
+            conversation_has_function_tools: has_function_tools && !suppress_tools,
+            suppress_tools,
+            suppress_reasoning_effort: config.is_some_and(|c| !c.include_reasoning_effort),
         };
         let last_assistant_is_final = messages
             .iter()
             .rev()
             .find_map(|msg| {
-                (msg.author.role == Role::Assistant)
-                    .then(|| msg.channel.as_deref() == Some("final"))
+                (msg.author.role == Role::Assistant).then(|| msg.channel == Some(Channel::Final))
             })
             .unwrap_or(false);
 
@@ -204,19 +744,27 @@ impl HarmonyEncoding {
 
         let first_final_idx = messages
             .iter()
-            .position(|msg| msg.channel.as_deref() == Some("final"));
+            .position(|msg| msg.channel == Some(Channel::Final));
 
-        let result = messages
-            .iter()
+        let messages = messages
+            .into_iter()
             .enumerate()
             .filter(|(idx, msg)| {
                 !(should_drop_analysis
                     && first_final_idx.is_some_and(|first| *idx < first)
-                    && msg.channel.as_deref() == Some("analysis"))
+                    && msg.channel == Some(Channel::Analysis))
             })
-            .try_for_each(|(_, msg)| self.render_into(msg, into, Some(&render_options)));
-        result?;
-        Ok(())
+            .map(|(_, msg)| msg)
+            .collect();
+        Ok((messages, render_options))
+    }
+
+    /// The [`RenderConversationConfig::extra_system_suffix`] to render, if
+    /// any. It is kept out of [`RenderOptions`] so that those stay `Copy`.
+    fn extra_system_suffix(config: Option<&RenderConversationConfig>) -> Option<&str> {
+        config
+            .and_then(|c| c.extra_system_suffix.as_deref())
+            .filter(|s| !s.is_empty())
     }
 
     /// Renders a conversation into a collection of tokens, adding the next turn role.
@@ -233,10 +781,96 @@ impl HarmonyEncoding {
         I: IntoIterator<Item = &'a Message>,
         B: Extend<Rank>,
     {
-        let _config = config.unwrap_or(&RenderConversationConfig::default());
         self.render_conversation_into(conversation, into, config)?;
+        self.render_completion_start_into(next_turn_role, into, config)
+    }
+
+    /// Like [`Self::render_conversation_for_completion`], but also reports
+    /// which options of `config` changed the output, e.g. to explain why two
+    /// renders of a conversation differ.
+    pub fn render_conversation_for_completion_reported<'a, I>(
+        &self,
+        conversation: I,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, RenderReport)>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let messages: Vec<&Message> = conversation.into_iter().collect();
+        let tokens = self.render_conversation_for_completion(
+            messages.iter().copied(),
+            next_turn_role,
+            config,
+        )?;
+        let (rendered, render_options) =
+            Self::plan_conversation_render(messages.iter().copied(), config)?;
+
+        let contents = || messages.iter().flat_map(|msg| &msg.content);
+        let system_contents = || {
+            contents().filter_map(|content| match content {
+                Content::SystemContent(sys) => Some(sys),
+                _ => None,
+            })
+        };
+        let has_developer_tools = contents().any(|content| {
+            matches!(content, Content::DeveloperContent(dev) if dev.tools.as_ref().is_some_and(|tools| !tools.is_empty()))
+        });
+        let report = RenderReport {
+            dropped_analysis_messages: messages.len() - rendered.len(),
+            rendered_function_tools: render_options.conversation_has_function_tools,
+            suppressed_tools: render_options.suppress_tools && has_developer_tools,
+            suppressed_reasoning_effort: render_options.suppress_reasoning_effort
+                && system_contents().any(|sys| sys.reasoning_effort.is_some()),
+            added_extra_system_suffix: Self::extra_system_suffix(config).is_some()
+                && system_contents().next().is_some(),
+            forced_channel: config.and_then(|c| c.force_channel.clone()),
+        };
+        Ok((tokens, report))
+    }
+
+    /// The tokens [`Self::render_conversation_for_completion`] appends after
+    /// the last message, e.g. `<|start|>assistant`, so a stored prompt can be
+    /// split from the model output deterministically.
+    ///
+    /// They depend on the next turn's role and on
+    /// [`RenderConversationConfig::force_channel`], which adds
+    /// `<|channel|>{channel}<|message|>`.
+    pub fn generation_prompt_tokens(
+        &self,
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let mut into = vec![];
+        self.render_completion_start_into(next_turn_role, &mut into, config)?;
+        Ok(into)
+    }
+
+    /// Render the header that opens the next turn of a completion prompt.
+    fn render_completion_start_into<B>(
+        &self,
+        next_turn_role: Role,
+        into: &mut B,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
         self.render_formatting_token_into(FormattingToken::Start, into)?;
         self.render_text_into(next_turn_role.as_str(), into)?;
+        if let Some(config) = config {
+            if let Some(channel) = &config.force_channel {
+                if let Some(allowed) = &config.allowed_channels {
+                    anyhow::ensure!(
+                        allowed.contains(channel),
+                        "forced channel {channel:?} is not one of the allowed channels {allowed:?}"
+                    );
+                }
+                self.render_formatting_token_into(FormattingToken::Channel, into)?;
+                self.render_text_into(channel.as_str(), into)?;
+                self.render_formatting_token_into(FormattingToken::Message, into)?;
+            }
+        }
         Ok(())
     }
 
@@ -259,6 +893,165 @@ impl HarmonyEncoding {
         Ok(into)
     }
 
+    /// Like [`Self::render_conversation_for_completion`] for an assistant
+    /// turn, but starts the answer with `prefill`, e.g. a forced `"Sure, "`.
+    ///
+    /// The tokens end with
+    /// `<|start|>assistant<|channel|>{channel}<|message|>{prefill}` and no
+    /// terminator, so the model continues the text and the prompt plus the
+    /// completion parse as one message. The channel is
+    /// [`RenderConversationConfig::force_channel`], or `final` if that is not
+    /// set. Without `prefill` this is the plain completion render.
+    pub fn render_conversation_for_prefill<'a, I>(
+        &self,
+        conversation: I,
+        prefill: Option<&str>,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let Some(prefill) = prefill else {
+            return self.render_conversation_for_completion(conversation, Role::Assistant, config);
+        };
+        let mut config = config.cloned().unwrap_or_default();
+        config
+            .force_channel
+            .get_or_insert_with(|| "final".to_string());
+        let mut tokens =
+            self.render_conversation_for_completion(conversation, Role::Assistant, Some(&config))?;
+        self.render_text_into(prefill, &mut tokens)?;
+        Ok(tokens)
+    }
+
+    /// Render messages that start every conversation, such as the system and
+    /// developer messages of an agent loop, once so that
+    /// [`Self::render_conversation_with_prefix`] can reuse their tokens.
+    pub fn render_prefix(&self, messages: &[Message]) -> anyhow::Result<RenderedPrefix> {
+        let (rendered, render_options) = Self::plan_conversation_render(messages, None)?;
+        let mut tokens = vec![];
+        for msg in rendered {
+            self.render_into(msg, &mut tokens, Some(&render_options))?;
+        }
+        Ok(RenderedPrefix {
+            messages: messages.to_vec(),
+            render_options,
+            tokens,
+        })
+    }
+
+    /// Render `prefix` followed by `rest`, producing the same tokens as
+    /// [`Self::render_conversation`] on the concatenated messages.
+    ///
+    /// The cached prefix tokens are reused unless `rest` or `config` changes
+    /// how the prefix renders, e.g. by adding function tools or dropping
+    /// analysis messages from it, in which case everything is re-rendered.
+    /// `prefix` must come from this encoding.
+    pub fn render_conversation_with_prefix(
+        &self,
+        prefix: &RenderedPrefix,
+        rest: &[Message],
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let mut into = vec![];
+        self.render_conversation_with_prefix_into(prefix, rest, &mut into, config)?;
+        Ok(into)
+    }
+
+    /// Like [`Self::render_conversation_with_prefix`], but adds the header of
+    /// the next turn like [`Self::render_conversation_for_completion`].
+    pub fn render_conversation_for_completion_with_prefix(
+        &self,
+        prefix: &RenderedPrefix,
+        rest: &[Message],
+        next_turn_role: Role,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<Vec<Rank>> {
+        let mut into = vec![];
+        self.render_conversation_with_prefix_into(prefix, rest, &mut into, config)?;
+        self.render_completion_start_into(next_turn_role, &mut into, config)?;
+        Ok(into)
+    }
+
+    /// Render `new_messages` onto the end of an already rendered
+    /// conversation, so agent loops do not re-render the whole history on
+    /// every turn.
+    ///
+    /// If `existing_tokens` ends with the header of a completion prompt, such
+    /// as the `<|start|>assistant` added by
+    /// [`Self::render_conversation_for_completion`], it is removed before the
+    /// new messages are rendered and added back after them. Tokens already in
+    /// the buffer are never rewritten, so `auto_drop_analysis` does not apply;
+    /// re-render the full conversation to drop old analysis messages.
+    pub fn append_messages(
+        &self,
+        existing_tokens: &mut Vec<Rank>,
+        new_messages: &[Message],
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<()> {
+        let keep_all = config.map(|c| RenderConversationConfig {
+            auto_drop_analysis: false,
+            ..c.clone()
+        });
+        let (messages, render_options) =
+            Self::plan_conversation_render(new_messages, keep_all.as_ref())?;
+        // Render before touching the buffer so it is left as is on error.
+        let suffix = Self::extra_system_suffix(config);
+        let mut rendered = Vec::new();
+        for msg in messages {
+            self.render_message_into(msg, &mut rendered, Some(&render_options), suffix)?;
+        }
+
+        let completion_start = existing_tokens
+            .iter()
+            .rposition(|&t| t == self.token_ids.start)
+            .filter(|&start| {
+                !existing_tokens[start..].iter().any(|&t| {
+                    t == self.token_ids.end
+                        || t == self.token_ids.call
+                        || t == self.token_ids.return_
+                })
+            });
+        let generation_prompt = match completion_start {
+            Some(start) => existing_tokens.split_off(start),
+            None => Vec::new(),
+        };
+        existing_tokens.extend(rendered);
+        existing_tokens.extend(generation_prompt);
+        Ok(())
+    }
+
+    fn render_conversation_with_prefix_into<B>(
+        &self,
+        prefix: &RenderedPrefix,
+        rest: &[Message],
+        into: &mut B,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        let (messages, render_options) =
+            Self::plan_conversation_render(prefix.messages.iter().chain(rest), config)?;
+        // the prefix is rendered without a config, so without a system suffix
+        let suffix = Self::extra_system_suffix(config);
+        let prefix_unchanged = render_options == prefix.render_options
+            && suffix.is_none()
+            && messages.len() >= prefix.messages.len()
+            && messages
+                .iter()
+                .zip(&prefix.messages)
+                .all(|(rendered, cached)| std::ptr::eq(*rendered, cached));
+        let tail = if prefix_unchanged {
+            into.extend(prefix.tokens.iter().copied());
+            &messages[prefix.messages.len()..]
+        } else {
+            &messages[..]
+        };
+        tail.iter()
+            .try_for_each(|msg| self.render_message_into(msg, into, Some(&render_options), suffix))
+    }
+
     /// Render a conversation for training.
     ///
     /// If the last message in the conversation is an assistant message to the
@@ -268,21 +1061,88 @@ impl HarmonyEncoding {
         conversation: I,
         config: Option<&RenderConversationConfig>,
     ) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = &'a Message>,
+    {
+        let (tokens, _) = self.render_conversation_for_training_with_mask(conversation, config)?;
+        Ok(tokens)
+    }
+
+    /// Like [`Self::render_conversation_for_training`], but also returns a
+    /// loss mask with one entry per token.
+    ///
+    /// The mask is `true` for every token of an assistant message, header and
+    /// terminator included, and `false` for system, developer, user and tool
+    /// messages. Assistant messages on the `analysis` channel are masked out
+    /// unless [`RenderConversationConfig::loss_on_analysis`] is set, and the
+    /// header of assistant messages (`<|start|>` through `<|message|>`) is
+    /// masked out if [`RenderConversationConfig::mask_assistant_header`] is.
+    pub fn render_conversation_for_training_with_mask<'a, I>(
+        &self,
+        conversation: I,
+        config: Option<&RenderConversationConfig>,
+    ) -> anyhow::Result<(Vec<Rank>, Vec<bool>)>
     where
         I: IntoIterator<Item = &'a Message>,
     {
         let messages: Vec<&Message> = conversation.into_iter().collect();
-        let mut out = vec![];
-        self.render_conversation_into(messages.iter().copied(), &mut out, config)?;
+        let (rendered, render_options) =
+            Self::plan_conversation_render(messages.iter().copied(), config)?;
+        let loss_on_analysis = config.is_none_or(|c| c.loss_on_analysis);
+        let mask_assistant_header = config.is_some_and(|c| c.mask_assistant_header);
+        let mut tokens = vec![];
+        let mut mask = vec![];
+        let suffix = Self::extra_system_suffix(config);
+        for msg in rendered {
+            let start = tokens.len();
+            self.render_message_into(msg, &mut tokens, Some(&render_options), suffix)?;
+            let in_loss = msg.author.role == Role::Assistant
+                && (loss_on_analysis || msg.channel != Some(Channel::Analysis));
+            if in_loss && mask_assistant_header {
+                // text is encoded without special tokens, so the first
+                // <|message|> is the one ending the header
+                let header_len = tokens[start..]
+                    .iter()
+                    .position(|&t| t == self.token_ids.message)
+                    .map_or(0, |idx| idx + 1);
+                mask.resize(start + header_len, false);
+            }
+            mask.resize(tokens.len(), in_loss);
+        }
         if let Some(last) = messages.last() {
-            if last.author.role == Role::Assistant && last.channel.as_deref() == Some("final") {
-                if let Some(last_token) = out.last_mut() {
+            if last.author.role == Role::Assistant && last.channel == Some(Channel::Final) {
+                if let Some(last_token) = tokens.last_mut() {
                     *last_token =
                         self.render_formatting_token(FormattingToken::EndMessageDoneSampling)?;
                 }
             }
         }
-        Ok(out)
+        Ok((tokens, mask))
+    }
+
+    /// Render a batch of conversations for training, pairing the tokens of
+    /// each with its loss mask (see
+    /// [`Self::render_conversation_for_training_with_mask`]).
+    ///
+    /// With the `parallel` feature the conversations are rendered on the rayon
+    /// thread pool. Results are returned in input order either way.
+    pub fn render_conversations_for_training(
+        &self,
+        conversations: &[Conversation],
+        config: &RenderConversationConfig,
+    ) -> anyhow::Result<Vec<(Vec<Rank>, Vec<bool>)>> {
+        let render = |conversation: &Conversation| {
+            self.render_conversation_for_training_with_mask(&conversation.messages, Some(config))
+        };
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            conversations.par_iter().map(render).collect()
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            conversations.iter().map(render).collect()
+        }
     }
 
     /// Render a conversation without appending a new role.
@@ -299,7 +1159,46 @@ impl HarmonyEncoding {
         Ok(out)
     }
 
+    /// Count the tokens [`Self::render_conversation`] would produce for
+    /// `conversation` without materializing the rendered token vector.
+    pub fn count_conversation_tokens(
+        &self,
+        conversation: &Conversation,
+        config: &RenderConversationConfig,
+    ) -> anyhow::Result<usize> {
+        let mut counter = TokenCounter::default();
+        self.render_conversation_into(&conversation.messages, &mut counter, Some(config))?;
+        Ok(counter.count)
+    }
+
+    /// Count the tokens [`Self::render`] produces for `message`: header,
+    /// content and terminator.
+    ///
+    /// Unless analysis messages get dropped, the counts of a conversation's
+    /// messages add up to [`Self::count_conversation_tokens`], and the
+    /// completion render adds [`Self::generation_prompt_tokens`] on top.
+    /// Pass the options of the conversation, since a system message renders
+    /// one line longer when the conversation has function tools.
+    ///
+    /// Takes the same arguments as [`Self::render`] and fails where it does,
+    /// rather than returning a count for a message that cannot be rendered.
+    pub fn count_message_tokens(
+        &self,
+        message: &Message,
+        render_options: Option<&RenderOptions>,
+    ) -> anyhow::Result<usize> {
+        let mut counter = TokenCounter::default();
+        Render::<Message>::render(self, message, &mut counter, render_options)?;
+        Ok(counter.count)
+    }
+
     /// Render a single message into tokens.
+    ///
+    /// The output is the canonical form of the message: assistant tool calls
+    /// put the recipient after the channel and the content type after a
+    /// space, whatever header order the message was parsed from. Rendering
+    /// parsed messages back therefore reproduces well-formed completions
+    /// token for token.
     pub fn render(
         &self,
         message: &Message,
@@ -310,6 +1209,38 @@ impl HarmonyEncoding {
         Ok(out)
     }
 
+    /// Render the tools block a developer message gets for `tools` as
+    /// function tools (see [`crate::chat::DeveloperContent::with_function_tools`]),
+    /// so a large static catalog can be tokenized once and cached.
+    ///
+    /// The block starts with `# Tools` and ends with `} // namespace
+    /// functions`. In the developer message it comes right after
+    /// `<|start|>developer<|message|>`, or after the instructions and a blank
+    /// line (`# Instructions\n\n{instructions}\n\n`), and is followed by
+    /// `<|end|>`. Spliced there it yields the same tokens as rendering the
+    /// whole message, since the tokenizer always splits text before `#`
+    /// following a run of newlines.
+    pub fn render_tools(&self, tools: &[crate::chat::ToolDescription]) -> Vec<Rank> {
+        let namespace = crate::chat::ToolNamespaceConfig::new("functions", None, tools.to_vec());
+        let tools = std::collections::BTreeMap::from([(namespace.name.clone(), namespace)]);
+        self.tokenizer
+            .encode_ordinary(&Self::template_tools_section(&tools))
+    }
+
+    /// Number of tokens the tools block for `tools` takes in a developer
+    /// message.
+    ///
+    /// For a non-empty `tools` this is the length of [`Self::render_tools`].
+    /// An empty slice returns `0`, unlike `render_tools(&[])`, which still
+    /// renders an empty `functions` namespace: a developer message without
+    /// tools leaves the block out, so it costs nothing.
+    pub fn count_tools_tokens(&self, tools: &[crate::chat::ToolDescription]) -> usize {
+        if tools.is_empty() {
+            return 0;
+        }
+        self.render_tools(tools).len()
+    }
+
     /// Render a single message into the provided buffer.
     pub fn render_into<B>(
         &self,
@@ -322,6 +1253,133 @@ impl HarmonyEncoding {
     {
         Render::<Message>::render(self, message, into, render_options)
     }
+
+    /// Render the header of an assistant tool call to `{namespace}.{tool}`
+    /// with JSON arguments, through `<|message|>`:
+    /// `<|start|>assistant<|channel|>commentary to={namespace}.{tool} <|constrain|>json<|message|>`.
+    ///
+    /// Appended to [`Self::render_conversation`] in place of the
+    /// `<|start|>assistant` of a completion prompt, it commits the model to
+    /// calling that tool, so it only has to generate the arguments. The
+    /// tokens are the same as the header of a rendered tool call message.
+    pub fn render_tool_call_prefix(
+        &self,
+        namespace: &str,
+        tool: &str,
+    ) -> anyhow::Result<Vec<Rank>> {
+        anyhow::ensure!(
+            [namespace, tool]
+                .iter()
+                .all(|part| !part.is_empty() && !part.contains(char::is_whitespace)),
+            "tool call recipient {namespace:?}.{tool:?} must be non-empty and without whitespace"
+        );
+        let constrain = self
+            .mapped_format_token(FormattingToken::ConstrainedFormat)
+            .ok_or_else(|| anyhow::anyhow!("no token is mapped to constrain the format"))?;
+        let header = Message::from_role_and_content(Role::Assistant, "")
+            .with_channel("commentary")
+            .with_recipient(format!("{namespace}.{tool}"))
+            .with_content_type(format!("{constrain}json"));
+        let mut tokens = Vec::new();
+        self.render_message_header_into(&header, &mut tokens)?;
+        Ok(tokens)
+    }
+}
+
+// Methods for fitting conversations into a token budget
+impl HarmonyEncoding {
+    /// Drop whole messages from a conversation until its completion render
+    /// (see [`Self::render_conversation_for_completion`]) fits into
+    /// `max_tokens`.
+    ///
+    /// System and developer messages, as well as the most recent user
+    /// message, are always kept. Fails if the conversation still does not fit
+    /// once the strategy has nothing left to drop.
+    pub fn truncate_conversation(
+        &self,
+        conversation: &Conversation,
+        max_tokens: usize,
+        strategy: TruncationStrategy,
+    ) -> anyhow::Result<TruncatedConversation> {
+        let count_tokens = |messages: &[Message]| -> anyhow::Result<usize> {
+            let mut counter = TokenCounter::default();
+            self.render_conversation_for_completion_into(
+                messages,
+                Role::Assistant,
+                &mut counter,
+                None,
+            )?;
+            Ok(counter.count)
+        };
+
+        let mut messages = conversation.messages.clone();
+        let original_tokens = count_tokens(&messages)?;
+        let mut tokens = original_tokens;
+        while tokens > max_tokens {
+            let last_user_idx = messages
+                .iter()
+                .rposition(|msg| msg.author.role == Role::User);
+            let is_droppable = |idx: usize, msg: &Message| {
+                !matches!(msg.author.role, Role::System | Role::Developer)
+                    && Some(idx) != last_user_idx
+            };
+            let drop_range = match strategy {
+                TruncationStrategy::DropOldestUserTurns => messages
+                    .iter()
+                    .enumerate()
+                    .position(|(idx, msg)| msg.author.role == Role::User && is_droppable(idx, msg))
+                    .map(|start| {
+                        let end = messages[start + 1..]
+                            .iter()
+                            .position(|msg| msg.author.role == Role::User)
+                            .map_or(messages.len(), |offset| start + 1 + offset);
+                        start..end
+                    }),
+                TruncationStrategy::DropAnalysisChannels => messages
+                    .iter()
+                    .position(|msg| {
+                        msg.author.role == Role::Assistant && msg.channel == Some(Channel::Analysis)
+                    })
+                    .map(|idx| idx..idx + 1),
+                TruncationStrategy::DropOldestPreservingSystem => messages
+                    .iter()
+                    .enumerate()
+                    .position(|(idx, msg)| is_droppable(idx, msg))
+                    .map(|idx| idx..idx + 1),
+            };
+            let Some(drop_range) = drop_range else {
+                anyhow::bail!(
+                    "conversation still needs {tokens} tokens after truncation with {strategy:?}, \
+                     exceeding the budget of {max_tokens}"
+                );
+            };
+            let mut idx = 0;
+            messages.retain(|msg| {
+                let keep = !drop_range.contains(&idx) || !is_droppable(idx, msg);
+                idx += 1;
+                keep
+            });
+            tokens = count_tokens(&messages)?;
+        }
+
+        Ok(TruncatedConversation {
+            removed_messages: conversation.messages.len() - messages.len(),
+            removed_tokens: original_tokens - tokens,
+            conversation: Conversation::from_messages(messages),
+        })
+    }
+}
+
+/// A rendering sink that only keeps track of how many tokens were emitted.
+#[derive(Default)]
+struct TokenCounter {
+    count: usize,
+}
+
+impl Extend<Rank> for TokenCounter {
+    fn extend<T: IntoIterator<Item = Rank>>(&mut self, iter: T) {
+        self.count += iter.into_iter().count();
+    }
 }
 
 // Rendering helper methods
@@ -360,6 +1418,9 @@ impl HarmonyEncoding {
         Ok(())
     }
 
+    /// Encode `text` as ordinary text. Message content, names and headers
+    /// all go through here, so a literal `<|end|>` in user text can never
+    /// become a formatting token.
     fn render_text_into<T, B>(&self, text: T, into: &mut B) -> anyhow::Result<()>
     where
         T: AsRef<str>,
@@ -369,15 +1430,153 @@ impl HarmonyEncoding {
         Ok(())
     }
 
+    /// Render `<|start|>` through `<|message|>` of `message`.
+    fn render_message_header_into<B>(&self, message: &Message, into: &mut B) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        self.render_formatting_token_into(FormattingToken::Start, into)?;
+
+        // render role then username
+        if matches!(message.author.role, Role::Tool) {
+            // for tools we only put the name
+            if let Some(name) = &message.author.name {
+                self.render_text_into(name, into)?;
+            } else {
+                anyhow::bail!("Tools should have a name!");
+            }
+        } else {
+            // For users and assistants we put both the role, and optionally the user name.
+            self.render_text_into(message.author.role.as_str(), into)?;
+            if let Some(name) = &message.author.name {
+                self.render_text_into(format!(":{name}"), into)?;
+            }
+        };
+
+        let recipient = message
+            .recipient
+            .as_deref()
+            .filter(|recipient| *recipient != "all");
+        // Assistant tool calls carry the recipient on the channel segment
+        // (`<|channel|>commentary to=functions.foo`), which is how the model
+        // emits them. Everything else puts it right after the role.
+        let recipient_after_channel =
+            message.author.role == Role::Assistant && message.channel.is_some();
+
+        // next render the header recipient, if there is one
+        if let Some(recipient) = recipient.filter(|_| !recipient_after_channel) {
+            self.render_text_into(format!(" to={recipient}"), into)?;
+        }
+
+        // next header channel
+        if let Some(channel) = &message.channel {
+            self.render_formatting_token_into(FormattingToken::Channel, into)?;
+            self.render_text_into(channel.as_str(), into)?;
+            if let Some(recipient) = recipient.filter(|_| recipient_after_channel) {
+                self.render_text_into(format!(" to={recipient}"), into)?;
+            }
+        }
+
+        // finally content type
+        if let Some(content_type) = &message.content_type {
+            self.render_content_type_into(content_type, into)?;
+        }
+
+        self.render_formatting_token_into(FormattingToken::Message, into)?;
+        Ok(())
+    }
+
+    /// Render the content type segment of a header, emitting a leading
+    /// `<|constrain|>` marker (or additional special token) as a special token
+    /// rather than as text.
+    fn render_content_type_into<B>(&self, content_type: &str, into: &mut B) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        let marked = self
+            .mapped_format_token(FormattingToken::ConstrainedFormat)
+            .into_iter()
+            .chain(self.additional_special_tokens.iter().map(String::as_str))
+            .find_map(|marker| {
+                content_type
+                    .strip_prefix(marker)
+                    .map(|format| (marker, format))
+            });
+        match marked {
+            Some((marker, format)) => {
+                self.render_text_into(" ", into)?;
+                into.extend(self.tokenizer.encode_with_special_tokens(marker));
+                self.render_text_into(format, into)?;
+            }
+            None => self.render_text_into(format!(" {content_type}"), into)?,
+        }
+        Ok(())
+    }
+
+    /// Parse a completion into messages.
+    ///
+    /// Each stop token ends exactly one message and adjacent messages are
+    /// never merged, so a commentary preamble ending in `<|end|>` and the
+    /// tool call after it come back as two messages on the same channel.
+    ///
+    /// Tokens that stop inside a message header fail with
+    /// [`ParseError::TruncatedMessage`]; a message cut off inside its
+    /// content, e.g. because generation hit `max_tokens`, is ended by the
+    /// end of the input. Use
+    /// [`Self::parse_messages_from_completion_tokens_lossy`] to tell the
+    /// cut off message apart.
     pub fn parse_messages_from_completion_tokens<I>(
         &self,
         tokens: I,
         role: Option<Role>,
+    ) -> Result<Vec<Message>, ParseError>
+    where
+        I: IntoIterator<Item = Rank>,
+    {
+        let options = ParseOptions {
+            assume_role: role,
+            ..Default::default()
+        };
+        let mut parser = StreamableParser::from_valid_options(self.clone(), &options);
+        for token in tokens {
+            parser.process(token)?;
+        }
+        parser.process_eos()?;
+        Ok(parser.into_messages())
+    }
+
+    /// Like [`Self::parse_messages_from_completion_tokens`], but for harmony
+    /// formatted text, e.g. copied from logs. Every `<|...|>` marker the
+    /// tokenizer knows is encoded as a special token.
+    pub fn parse_messages_from_completion_text(
+        &self,
+        text: &str,
+        role: Option<Role>,
+    ) -> Result<Vec<Message>, ParseError> {
+        let tokens = self.tokenizer.encode_with_special_tokens(text);
+        self.parse_messages_from_completion_tokens(tokens, role)
+    }
+
+    /// Like [`Self::parse_messages_from_completion_tokens`], but for tokens
+    /// that may start past the header of the first message, see
+    /// [`ParseOptions`].
+    pub fn parse_messages_from_completion_tokens_with_options<I>(
+        &self,
+        tokens: I,
+        options: &ParseOptions,
     ) -> anyhow::Result<Vec<Message>>
     where
         I: IntoIterator<Item = Rank>,
     {
-        let mut parser = StreamableParser::new(self.clone(), role)?;
+        if options.legacy_delimiters {
+            let tokens = self.translate_legacy_delimiters(tokens)?;
+            let options = ParseOptions {
+                legacy_delimiters: false,
+                ..options.clone()
+            };
+            return self.parse_messages_from_completion_tokens_with_options(tokens, &options);
+        }
+        let mut parser = StreamableParser::new_with_options(self.clone(), options)?;
         for token in tokens {
             parser.process(token)?;
         }
@@ -385,6 +1584,260 @@ impl HarmonyEncoding {
         Ok(parser.into_messages())
     }
 
+    /// Replace the legacy delimiters described at
+    /// [`ParseOptions::legacy_delimiters`] with harmony formatting tokens.
+    /// Text between the delimiters is re-tokenized as ordinary text and
+    /// special tokens are passed through.
+    fn translate_legacy_delimiters<I>(&self, tokens: I) -> anyhow::Result<Vec<Rank>>
+    where
+        I: IntoIterator<Item = Rank>,
+    {
+        let mut translated = Vec::new();
+        let mut run = Vec::new();
+        let mut in_header = false;
+        for token in tokens {
+            if self.tokenizer.is_special_token(token) {
+                self.translate_legacy_run(&run, &mut in_header, &mut translated)?;
+                run.clear();
+                translated.push(token);
+                in_header = in_header && token != self.token_ids.message;
+            } else {
+                run.push(token);
+            }
+        }
+        self.translate_legacy_run(&run, &mut in_header, &mut translated)?;
+        Ok(translated)
+    }
+
+    fn translate_legacy_run(
+        &self,
+        run: &[Rank],
+        in_header: &mut bool,
+        translated: &mut Vec<Rank>,
+    ) -> anyhow::Result<()> {
+        let bytes = self.tokenizer.decode_bytes(run)?;
+        let text = String::from_utf8_lossy(&bytes);
+        let mut rest = &*text;
+        loop {
+            let delimiters = if *in_header {
+                [
+                    (LEGACY_SEP, self.token_ids.message),
+                    ("\n", self.token_ids.message),
+                ]
+            } else {
+                [
+                    (LEGACY_START, self.token_ids.start),
+                    (LEGACY_END, self.token_ids.end),
+                ]
+            };
+            let next = delimiters
+                .into_iter()
+                .filter_map(|(delimiter, token)| {
+                    rest.find(delimiter).map(|idx| (idx, delimiter, token))
+                })
+                .min_by_key(|&(idx, ..)| idx);
+            let Some((idx, delimiter, token)) = next else {
+                translated.extend(self.tokenizer.encode_ordinary(rest));
+                return Ok(());
+            };
+            translated.extend(self.tokenizer.encode_ordinary(&rest[..idx]));
+            translated.push(token);
+            *in_header = token == self.token_ids.start;
+            rest = &rest[idx + delimiter.len()..];
+            if token == self.token_ids.end {
+                // logs usually put a newline between messages
+                rest = rest.trim_start();
+            }
+        }
+    }
+
+    /// Like [`Self::parse_messages_from_completion_tokens`], but also returns
+    /// the `[start, end)` range of token indices each message was parsed from.
+    ///
+    /// A span covers the whole message: its `<|start|>` token (if any), the
+    /// header, the content and the terminating token (`<|end|>`, `<|call|>` or
+    /// `<|return|>`). A message that is only terminated by the end of the
+    /// input extends to the last token.
+    pub fn parse_messages_from_completion_tokens_with_spans<I>(
+        &self,
+        tokens: I,
+        role: Option<Role>,
+    ) -> Result<Vec<(Message, std::ops::Range<usize>)>, ParseError>
+    where
+        I: IntoIterator<Item = Rank>,
+    {
+        let mut parser = StreamableParser::from_valid_options(
+            self.clone(),
+            &ParseOptions {
+                assume_role: role,
+                ..Default::default()
+            },
+        );
+        let mut spans = Vec::new();
+        let mut message_start = 0;
+        let mut num_tokens = 0;
+        for token in tokens {
+            parser.process(token)?;
+            num_tokens += 1;
+            if parser.messages().len() > spans.len() {
+                spans.push(message_start..num_tokens);
+                message_start = num_tokens;
+            }
+        }
+        parser.process_eos()?;
+        if parser.messages().len() > spans.len() {
+            spans.push(message_start..num_tokens);
+        }
+        Ok(parser.into_messages().into_iter().zip(spans).collect())
+    }
+
+    /// Like [`Self::parse_messages_from_completion_tokens`], but pairs each
+    /// message with the token that ended it, or `None` for a message that is
+    /// only terminated by the end of the input.
+    pub fn parse_messages_from_completion_tokens_with_terminators<I>(
+        &self,
+        tokens: I,
+        role: Option<Role>,
+    ) -> Result<Vec<(Message, Option<Terminator>)>, ParseError>
+    where
+        I: IntoIterator<Item = Rank>,
+    {
+        let mut parser = StreamableParser::from_valid_options(
+            self.clone(),
+            &ParseOptions {
+                assume_role: role,
+                ..Default::default()
+            },
+        );
+        for token in tokens {
+            parser.process(token)?;
+        }
+        parser.process_eos()?;
+        let terminators = std::mem::take(&mut parser.terminators);
+        Ok(parser
+            .into_messages()
+            .into_iter()
+            .zip(terminators)
+            .collect())
+    }
+
+    /// Like [`Self::parse_messages_from_completion_tokens`], but tolerates a
+    /// completion that ends in the middle of a message.
+    ///
+    /// Returns all fully terminated messages together with whatever could be
+    /// recovered from the unterminated tail. A tail cut off inside the header
+    /// reports the header fields read so far, a tail cut off inside the body
+    /// additionally reports the decoded content prefix.
+    pub fn parse_messages_from_completion_tokens_lossy<I>(
+        &self,
+        tokens: I,
+        role: Option<Role>,
+    ) -> Result<(Vec<Message>, Option<PartialMessage>), ParseError>
+    where
+        I: IntoIterator<Item = Rank>,
+    {
+        let mut parser = StreamableParser::from_valid_options(
+            self.clone(),
+            &ParseOptions {
+                assume_role: role,
+                ..Default::default()
+            },
+        );
+        for token in tokens {
+            parser.process(token)?;
+        }
+        let partial = parser.partial_message();
+        Ok((parser.into_messages(), partial))
+    }
+
+    /// Recover the [`SystemContent`] a prompt was rendered with.
+    ///
+    /// `tokens` may be a whole rendered prompt; the first complete system
+    /// message in it is parsed. This is the inverse of the system message
+    /// renderer, except that tool namespaces are skipped rather than parsed
+    /// back, so `tools` is always `None`. Fields whose line is absent are
+    /// `None` as well.
+    pub fn parse_system_content<I>(
+        &self,
+        tokens: I,
+    ) -> Result<SystemContent, ParseSystemContentError>
+    where
+        I: IntoIterator<Item = Rank>,
+    {
+        let (messages, _) = self
+            .parse_messages_from_completion_tokens_lossy(tokens, None)
+            .map_err(|e| ParseSystemContentError::InvalidTokens(e.into()))?;
+        let message = messages
+            .iter()
+            .find(|msg| msg.author.role == Role::System)
+            .ok_or(ParseSystemContentError::MissingSystemMessage)?;
+        let text: String = message
+            .content
+            .iter()
+            .filter_map(|c| match c {
+                Content::Text(TextContent { text }) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        parse_system_content_text(&text)
+    }
+
+    /// Parse a rendered conversation, such as a stored prompt, back into a
+    /// [`Conversation`]. This is the inverse of [`Self::render_conversation`].
+    ///
+    /// Unlike [`Self::parse_messages_from_completion_tokens`] every message
+    /// must start with its own `<|start|>` header, and a trailing completion
+    /// header like `<|start|>assistant` is ignored. System and developer
+    /// messages are turned back into [`SystemContent`] and
+    /// [`crate::chat::DeveloperContent`] when that content renders to exactly
+    /// the same text; otherwise, e.g. when they declare tools, which are not
+    /// parsed back, their text is kept as is.
+    pub fn parse_conversation_from_tokens(&self, tokens: &[Rank]) -> anyhow::Result<Conversation> {
+        let (mut messages, partial) =
+            self.parse_messages_from_completion_tokens_lossy(tokens.iter().copied(), None)?;
+        if let Some(partial) = partial {
+            anyhow::ensure!(
+                partial.content.is_empty() && partial.content_type.is_none(),
+                "conversation ends in the middle of a message"
+            );
+        }
+        let has_function_tools = messages.iter().any(|msg| {
+            msg.author.role == Role::System
+                && msg.content.iter().any(|c| {
+                    matches!(c, Content::Text(TextContent { text })
+                        if text.contains(FUNCTIONS_ON_COMMENTARY_LINE))
+                })
+        });
+        let render_options = RenderOptions {
+            conversation_has_function_tools: has_function_tools,
+            ..Default::default()
+        };
+        for message in &mut messages {
+            let [Content::Text(TextContent { text })] = message.content.as_slice() else {
+                continue;
+            };
+            let structured = match message.author.role {
+                Role::System => parse_system_content_text(text)
+                    .ok()
+                    .map(Content::SystemContent),
+                Role::Developer => text.strip_prefix("# Instructions\n\n").map(|instructions| {
+                    Content::DeveloperContent(
+                        crate::chat::DeveloperContent::new().with_instructions(instructions),
+                    )
+                }),
+                _ => None,
+            };
+            if let Some(structured) = structured {
+                let mut rendered = Vec::new();
+                Render::<Content>::render(self, &structured, &mut rendered, Some(&render_options))?;
+                if self.tokenizer.decode_utf8(&rendered).ok().as_ref() == Some(text) {
+                    message.content = vec![structured];
+                }
+            }
+        }
+        Ok(Conversation::from_messages(messages))
+    }
+
     /// Helper to convert a JSON schema (OpenAPI style) to a TypeScript type definition.
     fn json_schema_to_typescript(schema: &serde_json::Value, indent: &str) -> String {
         // Helper to check if this schema is an enum
@@ -776,9 +2229,39 @@ impl HarmonyEncoding {
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+/// Options for rendering a single message.
+///
+/// Options are added over time, so the struct cannot be built with a literal
+/// outside this crate: start from `RenderOptions::default()` and use the
+/// setters below.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
 pub struct RenderOptions {
     pub conversation_has_function_tools: bool,
+    /// Skip the tools of [`crate::chat::DeveloperContent`], see
+    /// [`RenderConversationConfig::suppress_tools`].
+    pub suppress_tools: bool,
+    /// Skip the `Reasoning:` line of [`SystemContent`], see
+    /// [`RenderConversationConfig::include_reasoning_effort`].
+    pub suppress_reasoning_effort: bool,
+}
+
+impl RenderOptions {
+    /// Set [`Self::conversation_has_function_tools`].
+    pub fn with_conversation_has_function_tools(mut self, has_function_tools: bool) -> Self {
+        self.conversation_has_function_tools = has_function_tools;
+        self
+    }
+    /// Set [`Self::suppress_tools`].
+    pub fn with_suppress_tools(mut self, suppress_tools: bool) -> Self {
+        self.suppress_tools = suppress_tools;
+        self
+    }
+    /// Set [`Self::suppress_reasoning_effort`].
+    pub fn with_suppress_reasoning_effort(mut self, suppress_reasoning_effort: bool) -> Self {
+        self.suppress_reasoning_effort = suppress_reasoning_effort;
+        self
+    }
 }
 
 trait Render<T: ?Sized> {
@@ -802,43 +2285,24 @@ impl Render<Message> for HarmonyEncoding {
     where
         B: Extend<Rank>,
     {
-        self.render_formatting_token_into(FormattingToken::Start, into)?;
-
-        // render role then username
-        if matches!(message.author.role, Role::Tool) {
-            // for tools we only put the name
-            if let Some(name) = &message.author.name {
-                self.render_text_into(name, into)?;
-            } else {
-                anyhow::bail!("Tools should have a name!");
-            }
-        } else {
-            // For users and assistants we put both the role, and optionally the user name.
-            self.render_text_into(message.author.role.as_str(), into)?;
-            if let Some(name) = &message.author.name {
-                self.render_text_into(format!(":{name}"), into)?;
-            }
-        };
-
-        // next render the header recipient, if there is one
-        if let Some(recipient) = &message.recipient {
-            if recipient != "all" {
-                self.render_text_into(format!(" to={recipient}"), into)?;
-            }
-        }
-
-        // next header channel
-        if let Some(channel) = &message.channel {
-            self.render_formatting_token_into(FormattingToken::Channel, into)?;
-            self.render_text_into(channel, into)?;
-        }
-
-        // finally content type
-        if let Some(content_type) = &message.content_type {
-            self.render_text_into(format!(" {content_type}"), into)?;
-        }
+        self.render_message_into(message, into, render_options, None)
+    }
+}
 
-        self.render_formatting_token_into(FormattingToken::Message, into)?;
+impl HarmonyEncoding {
+    /// Render a message, adding `extra_system_suffix` as the last section of
+    /// its [`SystemContent`], if any.
+    fn render_message_into<B>(
+        &self,
+        message: &Message,
+        into: &mut B,
+        render_options: Option<&RenderOptions>,
+        extra_system_suffix: Option<&str>,
+    ) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        self.render_message_header_into(message, into)?;
         for content in message.content.iter() {
             // SystemContent is only allowed in system messages
             if let crate::chat::Content::SystemContent(_) = content {
@@ -855,7 +2319,12 @@ impl Render<Message> for HarmonyEncoding {
                     message.author.role
                 );
             }
-            Render::<Content>::render(self, content, into, render_options)?;
+            match content {
+                Content::SystemContent(sys) => {
+                    self.render_system_content_into(sys, into, render_options, extra_system_suffix)?
+                }
+                _ => Render::<Content>::render(self, content, into, render_options)?,
+            }
         }
 
         // If there is a tool call we should render a tool call token
@@ -914,6 +2383,21 @@ impl Render<SystemContent> for HarmonyEncoding {
         into: &mut B,
         render_options: Option<&RenderOptions>,
     ) -> anyhow::Result<()>
+    where
+        B: Extend<Rank>,
+    {
+        self.render_system_content_into(sys, into, render_options, None)
+    }
+}
+
+impl HarmonyEncoding {
+    fn render_system_content_into<B>(
+        &self,
+        sys: &SystemContent,
+        into: &mut B,
+        render_options: Option<&RenderOptions>,
+        extra_system_suffix: Option<&str>,
+    ) -> anyhow::Result<()>
     where
         B: Extend<Rank>,
     {
@@ -923,10 +2407,16 @@ impl Render<SystemContent> for HarmonyEncoding {
         if let Some(model_id) = &sys.model_identity {
             top_section.push(model_id.clone());
         }
-        if let Some(knowledge_cutoff) = &sys.knowledge_cutoff {
+        // an empty value is treated like an unset one rather than rendering a
+        // line without a value
+        if let Some(knowledge_cutoff) = sys.knowledge_cutoff.as_deref().filter(|s| !s.is_empty()) {
             top_section.push(format!("Knowledge cutoff: {knowledge_cutoff}"));
         }
-        if let Some(conversation_start_date) = &sys.conversation_start_date {
+        if let Some(conversation_start_date) = sys
+            .conversation_start_date
+            .as_deref()
+            .filter(|s| !s.is_empty())
+        {
             top_section.push(format!("Current date: {conversation_start_date}"));
         }
         if !top_section.is_empty() {
@@ -934,13 +2424,9 @@ impl Render<SystemContent> for HarmonyEncoding {
         }
 
         let mut instructions_and_reasoning = Vec::<String>::new();
-        if let Some(effort) = sys.reasoning_effort {
-            let effort_str = match effort {
-                ReasoningEffort::Low => "low",
-                ReasoningEffort::Medium => "medium",
-                ReasoningEffort::High => "high",
-            };
-            instructions_and_reasoning.push(format!("Reasoning: {effort_str}"));
+        let suppress_reasoning_effort = render_options.is_some_and(|o| o.suppress_reasoning_effort);
+        if let Some(effort) = sys.reasoning_effort.filter(|_| !suppress_reasoning_effort) {
+            instructions_and_reasoning.push(format!("Reasoning: {}", effort.as_str()));
         }
         if !instructions_and_reasoning.is_empty() {
             sections.push(instructions_and_reasoning.join("\n"));
@@ -961,13 +2447,14 @@ impl Render<SystemContent> for HarmonyEncoding {
                 }
                 if render_options.is_some_and(|o| o.conversation_has_function_tools) {
                     channels_header.push('\n');
-                    channels_header.push_str(
-                        "Calls to these tools must go to the commentary channel: 'functions'.",
-                    );
+                    channels_header.push_str(FUNCTIONS_ON_COMMENTARY_LINE);
                 }
                 sections.push(channels_header);
             }
         }
+        if let Some(suffix) = extra_system_suffix.filter(|s| !s.is_empty()) {
+            sections.push(suffix.to_string());
+        }
         let formatted = sections.join("\n\n");
         self.render_text_into(&formatted, into)?;
         Ok(())
@@ -980,7 +2467,7 @@ impl Render<crate::chat::DeveloperContent> for HarmonyEncoding {
         &self,
         dev: &crate::chat::DeveloperContent,
         into: &mut B,
-        _render_options: Option<&RenderOptions>,
+        render_options: Option<&RenderOptions>,
     ) -> anyhow::Result<()>
     where
         B: Extend<Rank>,
@@ -992,7 +2479,8 @@ impl Render<crate::chat::DeveloperContent> for HarmonyEncoding {
             sections.push(instr.clone());
         }
 
-        if let Some(tools) = &dev.tools {
+        let suppress_tools = render_options.is_some_and(|o| o.suppress_tools);
+        if let Some(tools) = dev.tools.as_ref().filter(|_| !suppress_tools) {
             if !tools.is_empty() {
                 sections.push(Self::template_tools_section(tools));
             }
@@ -1003,19 +2491,159 @@ impl Render<crate::chat::DeveloperContent> for HarmonyEncoding {
     }
 }
 
+/// Line the system message gets when the conversation declares function tools.
+const FUNCTIONS_ON_COMMENTARY_LINE: &str =
+    "Calls to these tools must go to the commentary channel: 'functions'.";
+
+/// Parse the text of a system message as produced by
+/// `Render<SystemContent>`, one line at a time.
+fn parse_system_content_text(text: &str) -> Result<SystemContent, ParseSystemContentError> {
+    let mut sys = SystemContent {
+        model_identity: None,
+        reasoning_effort: None,
+        tools: None,
+        conversation_start_date: None,
+        knowledge_cutoff: None,
+        channel_config: None,
+    };
+    let mut in_tools = false;
+    let mut offset = 0;
+    for (idx, line) in text.split('\n').enumerate() {
+        let line_offset = offset;
+        offset += line.len() + 1;
+        if line.is_empty() {
+            continue;
+        }
+        if in_tools {
+            // the tools section runs until the channel section, if any
+            if !line.starts_with("# Valid channels: ") {
+                continue;
+            }
+            in_tools = false;
+        }
+        if let Some(cutoff) = line.strip_prefix("Knowledge cutoff: ") {
+            sys.knowledge_cutoff = Some(cutoff.to_string());
+        } else if let Some(date) = line.strip_prefix("Current date: ") {
+            sys.conversation_start_date = Some(date.to_string());
+        } else if let Some(effort) = line.strip_prefix("Reasoning: ") {
+            sys.reasoning_effort = Some(effort.parse().map_err(
+                |ParseReasoningEffortError(effort)| {
+                    ParseSystemContentError::InvalidReasoningEffort(effort)
+                },
+            )?);
+        } else if let Some(channels) = line.strip_prefix("# Valid channels: ") {
+            let (channels, channel_required) =
+                match channels.strip_suffix(" Channel must be included for every message.") {
+                    Some(channels) => (channels, true),
+                    None => (channels, false),
+                };
+            let channels = channels.strip_suffix('.').unwrap_or(channels);
+            sys.channel_config = Some(crate::chat::ChannelConfig {
+                valid_channels: channels.split(", ").map(str::to_string).collect(),
+                channel_required,
+            });
+        } else if line == FUNCTIONS_ON_COMMENTARY_LINE {
+            // derived from the conversation's tools rather than the system content
+        } else if line == "# Tools" {
+            in_tools = true;
+        } else if idx == 0 {
+            sys.model_identity = Some(line.to_string());
+        } else {
+            return Err(ParseSystemContentError::UnrecognizedSegment {
+                segment: line.to_string(),
+                offset: line_offset,
+            });
+        }
+    }
+    Ok(sys)
+}
+
+/// Remove whitespace the model sometimes emits right after the channel marker
+/// or after `to=`, e.g. `<|channel|> commentary` or `to= functions.x`, so the
+/// value directly follows its marker.
+fn normalize_header_whitespace(header: &str, channel_marker: Option<&str>) -> String {
+    let mut out = header.to_string();
+    for marker in channel_marker.into_iter().chain(["to="]) {
+        let mut normalized = String::with_capacity(out.len());
+        let mut rest = out.as_str();
+        while let Some(idx) = rest.find(marker) {
+            normalized.push_str(&rest[..idx + marker.len()]);
+            rest = rest[idx + marker.len()..].trim_start();
+        }
+        normalized.push_str(rest);
+        out = normalized;
+    }
+    out
+}
+
+/// Remove and return the longest prefix of `pending` that decodes as UTF-8,
+/// keeping an incomplete trailing sequence in place. Invalid sequences are
+/// replaced with U+FFFD.
+fn drain_complete_utf8(pending: &mut Vec<u8>) -> String {
+    let mut out = String::new();
+    loop {
+        match std::str::from_utf8(pending) {
+            Ok(valid) => {
+                out.push_str(valid);
+                pending.clear();
+                return out;
+            }
+            Err(e) => {
+                let valid_up_to = e.valid_up_to();
+                out.push_str(&String::from_utf8_lossy(&pending[..valid_up_to]));
+                match e.error_len() {
+                    Some(invalid_len) => {
+                        out.push(char::REPLACEMENT_CHARACTER);
+                        pending.drain(..valid_up_to + invalid_len);
+                    }
+                    None => {
+                        pending.drain(..valid_up_to);
+                        return out;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Like [`drain_complete_utf8`], but fails on bytes that are invalid
+/// whatever follows them instead of replacing them.
+fn drain_valid_utf8(pending: &mut Vec<u8>) -> Result<String, ParseError> {
+    let valid_up_to = match std::str::from_utf8(pending) {
+        Ok(_) => pending.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => return Err(ParseError::InvalidContentEncoding),
+    };
+    let rest = pending.split_off(valid_up_to);
+    String::from_utf8(std::mem::replace(pending, rest))
+        .map_err(|_| ParseError::InvalidContentEncoding)
+}
+
 /// Incremental parser that can consume tokens one by one.
 ///
 /// It keeps track of all tokens seen so far, exposes all fully parsed messages
 /// and retains the partially parsed state of the current message.
 pub struct StreamableParser {
     encoding: HarmonyEncoding,
+    /// Role the parser was created with, restored by [`Self::reset`].
+    initial_role: Option<Role>,
+    /// Whether the parser was created to start inside message content.
+    starts_in_content: bool,
+    /// See [`ParseOptions::normalize_headers`].
+    normalize_headers: bool,
+    /// See [`ParseOptions::allowed_channels`].
+    allowed_channels: Option<Vec<String>>,
     next_role: Option<Role>,
     tokens: Vec<Rank>,
     messages: Vec<Message>,
+    /// The token that ended each of `messages`.
+    terminators: Vec<Option<Terminator>>,
     state: StreamState,
     stop_tokens: HashSet<Rank>,
     last_content_delta: Option<String>,
-    undecoded_tokens: Vec<Rank>,
+    /// Trailing bytes of the current content that do not form a complete
+    /// UTF-8 sequence yet and have therefore not been emitted as a delta.
+    undecoded_bytes: Vec<u8>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -1023,6 +2651,10 @@ pub enum StreamState {
     ExpectStart,
     Header {
         header_tokens: Vec<Rank>,
+        /// Channel read from the header so far, once its value is terminated.
+        channel: Option<String>,
+        /// Recipient read from the header so far, once its value is terminated.
+        recipient: Option<String>,
     },
     Content {
         header: ParsedHeader,
@@ -1030,34 +2662,156 @@ pub enum StreamState {
     },
 }
 
+/// Options for parsing completions that do not start at a message boundary.
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Role of the first message, whose `<|start|>` token and role are not
+    /// part of the tokens. Without it, the tokens must start with `<|start|>`.
+    pub assume_role: Option<Role>,
+    /// The tokens start inside the content of the first message, after its
+    /// `<|message|>` token, e.g. when resuming a stream. Since the header is
+    /// missing, that message has no channel, recipient or content type.
+    /// Requires `assume_role`.
+    pub starts_in_content: bool,
+    /// Also accept the older ChatML-style delimiters: `<|im_start|>` starts
+    /// a message, its header runs to the first `<|im_sep|>` or newline, and
+    /// `<|im_end|>` ends it. They are not special tokens in the harmony
+    /// vocabulary, so they are recognized in the decoded text. Only supported
+    /// by [`HarmonyEncoding::parse_messages_from_completion_tokens_with_options`].
+    /// Off by default.
+    pub legacy_delimiters: bool,
+    /// Accept the `to=` recipient and the `<|constrain|>` content type in
+    /// any order after the role and channel, and drop the `to=all`
+    /// recipient, which renders the same as none. Headers the model emits
+    /// in a different order then parse to the same message, and rendering
+    /// a parsed message and parsing it again yields that message unchanged.
+    pub normalize_headers: bool,
+    /// Channels a message header may name. A header with any other channel
+    /// fails with [`ParseError::UnknownChannel`]. `None`, the default,
+    /// accepts any channel.
+    pub allowed_channels: Option<Vec<String>>,
+}
+
+const LEGACY_START: &str = "<|im_start|>";
+const LEGACY_SEP: &str = "<|im_sep|>";
+const LEGACY_END: &str = "<|im_end|>";
+
+/// What a single token changed, as reported by
+/// [`StreamableParser::process_event`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamEvent {
+    /// The `<|message|>` token ended the header of a new message.
+    HeaderComplete {
+        channel: Option<String>,
+        recipient: Option<String>,
+    },
+    /// Text appended to the content of the current message.
+    ContentDelta(String),
+    /// A stop token ended the current message. Its content is authoritative:
+    /// bytes of a character that was cut off by the stop token are only
+    /// part of the message, not of any delta.
+    MessageComplete(Message),
+}
+
 impl StreamableParser {
     /// Create a new streaming parser starting with the given role.
     pub fn new(encoding: HarmonyEncoding, role: Option<Role>) -> anyhow::Result<Self> {
-        let stop_tokens = encoding.stop_tokens()?;
-        let (state, next_role) = match role {
-            Some(role) => (
-                StreamState::Header {
-                    header_tokens: Vec::new(),
-                },
-                Some(role),
-            ),
-            None => (StreamState::ExpectStart, None),
-        };
-        Ok(Self {
+        Self::new_with_options(
+            encoding,
+            &ParseOptions {
+                assume_role: role,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Create a new streaming parser for tokens described by `options`.
+    pub fn new_with_options(
+        encoding: HarmonyEncoding,
+        options: &ParseOptions,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !options.starts_in_content || options.assume_role.is_some(),
+            "starting in the middle of the message content requires assume_role"
+        );
+        anyhow::ensure!(
+            !options.legacy_delimiters,
+            "legacy delimiters are only supported by parse_messages_from_completion_tokens_with_options"
+        );
+        Ok(Self::from_valid_options(encoding, options))
+    }
+
+    /// [`Self::new_with_options`] for options that passed its checks.
+    fn from_valid_options(encoding: HarmonyEncoding, options: &ParseOptions) -> Self {
+        let stop_tokens = encoding.stop_token_ids().iter().copied().collect();
+        let next_role = options.assume_role.clone();
+        let state = Self::initial_state(options, Vec::new());
+        Self {
             encoding,
+            initial_role: next_role.clone(),
+            starts_in_content: options.starts_in_content,
+            normalize_headers: options.normalize_headers,
+            allowed_channels: options.allowed_channels.clone(),
             next_role,
             tokens: Vec::new(),
             messages: Vec::new(),
+            terminators: Vec::new(),
             state,
             stop_tokens,
             last_content_delta: None,
-            undecoded_tokens: Vec::new(),
-        })
+            undecoded_bytes: Vec::new(),
+        }
+    }
+
+    /// Return to the state of a freshly constructed parser, keeping the
+    /// capacity of the internal buffers so the parser can be reused.
+    pub fn reset(&mut self) {
+        let mut buffer = match std::mem::replace(&mut self.state, StreamState::ExpectStart) {
+            StreamState::ExpectStart => Vec::new(),
+            StreamState::Header { header_tokens, .. } => header_tokens,
+            StreamState::Content { content_tokens, .. } => content_tokens,
+        };
+        buffer.clear();
+        let options = ParseOptions {
+            assume_role: self.initial_role.clone(),
+            starts_in_content: self.starts_in_content,
+            normalize_headers: self.normalize_headers,
+            ..Default::default()
+        };
+        self.state = Self::initial_state(&options, buffer);
+        self.next_role = self.initial_role.clone();
+        self.tokens.clear();
+        self.messages.clear();
+        self.terminators.clear();
+        self.last_content_delta = None;
+        self.undecoded_bytes.clear();
+    }
+
+    fn initial_state(options: &ParseOptions, buffer: Vec<Rank>) -> StreamState {
+        match &options.assume_role {
+            Some(role) if options.starts_in_content => StreamState::Content {
+                header: ParsedHeader {
+                    author: Author {
+                        role: role.clone(),
+                        name: None,
+                    },
+                    recipient: None,
+                    channel: None,
+                    content_type: None,
+                },
+                content_tokens: buffer,
+            },
+            Some(_) => StreamState::Header {
+                header_tokens: buffer,
+                channel: None,
+                recipient: None,
+            },
+            None => StreamState::ExpectStart,
+        }
     }
 
     /// Consume a single token and update the internal state.
-    /// Consume a single token and update the internal state.
-    fn process_next(&mut self, token: Option<Rank>) -> anyhow::Result<&mut Self> {
+    fn process_next(&mut self, token: Option<Rank>) -> Result<&mut Self, ParseError> {
         if let Some(token) = token {
             self.tokens.push(token);
         }
@@ -1065,21 +2819,20 @@ impl StreamableParser {
         let next_role_clone = self.next_role.clone();
         match &mut self.state {
             StreamState::ExpectStart => {
-                let start = self
-                    .encoding
-                    .render_formatting_token(FormattingToken::Start)?;
+                let start = self.encoding.token_ids.start;
                 match token {
                     Some(token) if token == start => {
                         self.state = StreamState::Header {
                             header_tokens: Vec::new(),
+                            channel: None,
+                            recipient: None,
                         };
                     }
                     Some(token) => {
-                        anyhow::bail!(
-                            "Unexpected token {} while expecting start token {}",
+                        return Err(ParseError::MissingMessageDelimiter {
+                            position: self.tokens.len() - 1,
                             token,
-                            start
-                        );
+                        });
                     }
                     None => {
                         // receiving EOS while waiting for start token is actually fine
@@ -1088,10 +2841,12 @@ impl StreamableParser {
                     }
                 }
             }
-            StreamState::Header { header_tokens } => {
-                let msg_tok = self
-                    .encoding
-                    .render_formatting_token(FormattingToken::Message)?;
+            StreamState::Header {
+                header_tokens,
+                channel,
+                recipient,
+            } => {
+                let msg_tok = self.encoding.token_ids.message;
                 match token {
                     Some(token) if token == msg_tok => {
                         // Clone the tokens and next_role, then clear the state before parsing
@@ -1108,13 +2863,27 @@ impl StreamableParser {
                         };
                     }
                     Some(token) => {
+                        let position = self.tokens.len() - 1;
+                        // a new message or a terminator cannot appear before <|message|>
+                        if token == self.encoding.token_ids.start
+                            || self.stop_tokens.contains(&token)
+                        {
+                            return Err(ParseError::UnexpectedTokenInHeader { position, token });
+                        }
+                        if self.encoding.tokenizer().decode_bytes([token]).is_err() {
+                            return Err(ParseError::InvalidToken { position, token });
+                        }
                         header_tokens.push(token);
+                        if let Ok(header_string) =
+                            self.encoding.tokenizer().decode_utf8(&*header_tokens)
+                        {
+                            let (new_channel, new_recipient) =
+                                Self::scan_header_segments(&self.encoding, &header_string);
+                            *channel = new_channel;
+                            *recipient = new_recipient;
+                        }
                     }
-                    None => {
-                        anyhow::bail!(
-                            "Unexpected EOS while waiting for message header to complete"
-                        );
-                    }
+                    None => return Err(ParseError::TruncatedMessage),
                 }
             }
             StreamState::Content {
@@ -1126,23 +2895,20 @@ impl StreamableParser {
                         // this is a stop token, dont parse and mark EOS
                         true
                     } else {
-                        self.undecoded_tokens.push(token);
-                        // some tokens might not appropriately decode on their own. If they don't
-                        // we will collect them until they eventually decode
-                        match self
-                            .encoding
-                            .tokenizer()
-                            .decode_utf8(&self.undecoded_tokens)
-                        {
-                            Ok(decoded) => {
-                                content_tokens.extend(self.undecoded_tokens.iter().copied());
-                                self.last_content_delta = Some(decoded);
-                                self.undecoded_tokens.clear();
-                            }
-                            Err(_) => {
-                                self.last_content_delta = None;
-                            }
-                        }
+                        content_tokens.push(token);
+                        // a token may end in the middle of a multi-byte character, so only
+                        // emit complete code points and hold back the dangling bytes
+                        let bytes =
+                            self.encoding
+                                .tokenizer()
+                                .decode_bytes([token])
+                                .map_err(|_| ParseError::InvalidToken {
+                                    position: self.tokens.len() - 1,
+                                    token,
+                                })?;
+                        self.undecoded_bytes.extend(bytes);
+                        let delta = drain_valid_utf8(&mut self.undecoded_bytes)?;
+                        self.last_content_delta = (!delta.is_empty()).then_some(delta);
                         // this was not an EOS
                         false
                     }
@@ -1151,43 +2917,114 @@ impl StreamableParser {
                     true
                 };
                 if is_eos {
-                    let text = self.encoding.tokenizer().decode_utf8(content_tokens)?;
+                    // every complete character has been emitted as a delta already, so
+                    // bytes still held back here are a character the message cut off
+                    let bytes = self
+                        .encoding
+                        .tokenizer()
+                        .decode_bytes(&*content_tokens)
+                        .map_err(|e| ParseError::InvalidToken {
+                            position: self.tokens.len() - 1,
+                            token: e.token,
+                        })?;
+                    let text =
+                        String::from_utf8(bytes).map_err(|_| ParseError::InvalidContentEncoding)?;
                     let message = Message {
                         author: header.author.clone(),
                         recipient: header.recipient.clone(),
-                        channel: header.channel.clone(),
+                        channel: header.channel.clone().map(Channel::from_name),
                         content_type: header.content_type.clone(),
                         content: vec![Content::Text(TextContent { text })],
                     };
                     self.messages.push(message);
+                    let stop_config = self.encoding.assistant_stop_config();
+                    self.terminators
+                        .push(token.and_then(|token| stop_config.terminator(token)));
                     self.state = StreamState::ExpectStart;
                     self.last_content_delta = None;
-                    self.undecoded_tokens.clear();
+                    self.undecoded_bytes.clear();
                 }
             }
         }
         Ok(self)
     }
 
-    pub fn process(&mut self, token: Rank) -> anyhow::Result<&mut Self> {
+    pub fn process(&mut self, token: Rank) -> Result<&mut Self, ParseError> {
         self.process_next(Some(token))
     }
 
-    pub fn process_eos(&mut self) -> anyhow::Result<&mut Self> {
+    pub fn process_eos(&mut self) -> Result<&mut Self, ParseError> {
         self.process_next(None)?;
         Ok(self)
     }
 
+    /// Process a chunk of tokens, with the same result as calling
+    /// [`Self::process`] on each of them.
+    ///
+    /// Afterwards [`Self::last_content_delta`] holds all content text the
+    /// chunk added, including the end of messages it completed. Characters
+    /// and headers split across chunks are handled like with single tokens.
+    /// On error the tokens before the failing one stay processed.
+    pub fn process_slice(&mut self, tokens: &[Rank]) -> Result<(), ParseError> {
+        let mut delta = String::new();
+        for &token in tokens {
+            self.last_content_delta = None;
+            let result = self.process_next(Some(token)).map(|_| ());
+            if let Some(token_delta) = &self.last_content_delta {
+                delta.push_str(token_delta);
+            }
+            result?;
+        }
+        self.last_content_delta = (!delta.is_empty()).then_some(delta);
+        Ok(())
+    }
+
+    /// Like [`Self::process`], but reports what the token changed as a
+    /// [`StreamEvent`], so the parser can feed an event stream directly.
+    ///
+    /// Returns `None` for tokens that only extend the header, start a new
+    /// message or add bytes that do not complete a character yet.
+    pub fn process_event(&mut self, token: Rank) -> Result<Option<StreamEvent>, ParseError> {
+        let was_in_header = matches!(self.state, StreamState::Header { .. });
+        let message_count = self.messages.len();
+        self.last_content_delta = None;
+        self.process_next(Some(token))?;
+
+        if self.messages.len() > message_count {
+            return Ok(self
+                .messages
+                .last()
+                .cloned()
+                .map(StreamEvent::MessageComplete));
+        }
+        if let StreamState::Content { header, .. } = &self.state {
+            if was_in_header {
+                return Ok(Some(StreamEvent::HeaderComplete {
+                    channel: header.channel.clone(),
+                    recipient: header.recipient.clone(),
+                }));
+            }
+        }
+        Ok(self
+            .last_content_delta
+            .clone()
+            .map(StreamEvent::ContentDelta))
+    }
+
     fn parse_header_from_tokens(
         &self,
         header_tokens: &[Rank],
         role: Option<Role>,
-    ) -> anyhow::Result<ParsedHeader> {
+    ) -> Result<ParsedHeader, ParseError> {
         let mut header_string = self
             .encoding
             .tokenizer()
             .decode_utf8(header_tokens)
-            .context("could not decode header")?;
+            .map_err(|_| ParseError::InvalidHeaderEncoding)?;
+        header_string = normalize_header_whitespace(
+            &header_string,
+            self.encoding.mapped_format_token(FormattingToken::Channel),
+        );
 
         let mut channel: Option<String> = None;
         if let Some(channel_marker) = self.encoding.mapped_format_token(FormattingToken::Channel) {
@@ -1198,7 +3035,12 @@ impl StreamableParser {
                     .unwrap_or(after_marker.len());
                 let channel_value = &after_marker[..channel_end];
                 if channel_value.is_empty() {
-                    anyhow::bail!("channel marker present but no channel value found in header");
+                    return Err(ParseError::EmptyChannel);
+                }
+                if let Some(allowed) = &self.allowed_channels {
+                    if !allowed.iter().any(|c| c == channel_value) {
+                        return Err(ParseError::UnknownChannel(channel_value.to_string()));
+                    }
                 }
                 channel = Some(channel_value.to_string());
 
@@ -1213,34 +3055,47 @@ impl StreamableParser {
         // removed the channel section.
         header_string = header_string.trim().to_string();
 
-        // If the constrained format marker is present but not preceded by
-        // whitespace (e.g. "to=foo<|constrain|>json"), insert a space before
-        // the marker so that splitting on whitespace treats the content type
-        // as a separate token.
-        if let Some(constrain_marker) = self
+        // If the constrained format marker (or an additional special token)
+        // is present but not preceded by whitespace (e.g.
+        // "to=foo<|constrain|>json"), insert a space before the marker so that
+        // splitting on whitespace treats the content type as a separate token.
+        let markers = self
             .encoding
             .mapped_format_token(FormattingToken::ConstrainedFormat)
-        {
-            if header_string.contains(constrain_marker) {
+            .into_iter()
+            .chain(
+                self.encoding
+                    .additional_special_tokens
+                    .iter()
+                    .map(String::as_str),
+            );
+        for marker in markers {
+            if header_string.contains(marker) {
                 header_string = header_string
-                    .replace(constrain_marker, &format!(" {constrain_marker}"))
+                    .replace(marker, &format!(" {marker}"))
                     .trim()
                     .to_string();
             }
         }
 
         let mut parts: Vec<&str> = header_string.split_ascii_whitespace().collect();
+        // a `<|constrain|>` without a grammar name after it constrains nothing,
+        // so treat the content as unconstrained rather than as a recipient
+        if let Some(marker) = self
+            .encoding
+            .mapped_format_token(FormattingToken::ConstrainedFormat)
+        {
+            parts.retain(|part| *part != marker);
+        }
 
         let mut role_str_opt: Option<String> = None;
         let role = match role {
             Some(r) => r,
             None => {
-                let role_str = parts
-                    .first()
-                    .context("message header did not contain a role")?;
+                let role_str = parts.first().ok_or(ParseError::MissingRole)?;
                 role_str_opt = Some((*role_str).to_string());
                 let parsed_role = Role::try_from(*role_str);
-                let out = match parsed_role {
+                match parsed_role {
                     Ok(r) => r,
                     Err(_) => {
                         // If recipient is present, treat as tool call
@@ -1248,11 +3103,10 @@ impl StreamableParser {
                             parts.remove(0); // Remove the unknown role string
                             Role::Tool
                         } else {
-                            return Err(anyhow::anyhow!("Unknown role: {}", role_str));
+                            return Err(ParseError::UnknownRole(role_str.to_string()));
                         }
                     }
-                };
-                out
+                }
             }
         };
 
@@ -1265,7 +3119,30 @@ impl StreamableParser {
         let mut recipient: Option<String> = None;
         let mut content_type: Option<String> = None;
 
-        if !parts.is_empty() {
+        if self.normalize_headers {
+            if let Some(idx) = parts.iter().position(|part| part.starts_with("to=")) {
+                recipient = Some(parts.remove(idx)["to=".len()..].to_string());
+            }
+            if let Some(marker) = self
+                .encoding
+                .mapped_format_token(FormattingToken::ConstrainedFormat)
+            {
+                if let Some(idx) = parts.iter().position(|part| part.starts_with(marker)) {
+                    content_type = Some(parts.remove(idx).to_string());
+                }
+            }
+            // with one of them known, a single remaining part is the other,
+            // e.g. `json` without `<|constrain|>`
+            if parts.len() == 1 {
+                if recipient.is_none() && content_type.is_some() {
+                    recipient = parts.pop().map(str::to_string);
+                } else if recipient.is_some() && content_type.is_none() {
+                    content_type = parts.pop().map(str::to_string);
+                }
+            }
+        }
+
+        if !parts.is_empty() && recipient.is_none() && content_type.is_none() {
             // Determine whether the last token is a content-type or part of the
             // recipient specification.
             let num_parts = parts.len();
@@ -1293,11 +3170,20 @@ impl StreamableParser {
                 }
             }
         }
-        anyhow::ensure!(
-            parts.is_empty(),
-            "unexpected tokens remaining in message header: {:?}",
-            parts
-        );
+        if !parts.is_empty() {
+            return Err(ParseError::UnexpectedHeaderContent(
+                parts.into_iter().map(str::to_string).collect(),
+            ));
+        }
+
+        if self.normalize_headers && recipient.as_deref() == Some("all") {
+            recipient = None;
+        }
+        if let Some(recipient) = &recipient {
+            if recipient.split('.').any(str::is_empty) {
+                return Err(ParseError::InvalidToolRecipient(recipient.clone()));
+            }
+        }
 
         let author = if role == Role::Tool {
             let name = role_str_opt;
@@ -1313,18 +3199,133 @@ impl StreamableParser {
         })
     }
 
+    /// Extract the channel and recipient from an incomplete header string.
+    ///
+    /// A value is only reported once something follows it, since until then
+    /// the next token may still extend it.
+    fn scan_header_segments(
+        encoding: &HarmonyEncoding,
+        header_string: &str,
+    ) -> (Option<String>, Option<String>) {
+        fn terminated_value(after_marker: &str) -> Option<String> {
+            let end = after_marker.find(|c: char| c.is_whitespace() || c == '<')?;
+            let value = &after_marker[..end];
+            (!value.is_empty()).then(|| value.to_string())
+        }
+
+        let header_string = &normalize_header_whitespace(
+            header_string,
+            encoding.mapped_format_token(FormattingToken::Channel),
+        );
+        let channel = encoding
+            .mapped_format_token(FormattingToken::Channel)
+            .and_then(|marker| header_string.find(marker).map(|idx| idx + marker.len()))
+            .and_then(|start| terminated_value(&header_string[start..]));
+        let recipient = header_string
+            .find("to=")
+            .and_then(|idx| terminated_value(&header_string[idx + "to=".len()..]));
+        (channel, recipient)
+    }
+
+    /// Describe the message that is currently being parsed, if any.
+    fn partial_message(&self) -> Option<PartialMessage> {
+        match &self.state {
+            StreamState::ExpectStart => None,
+            StreamState::Header { header_tokens, .. } if header_tokens.is_empty() => None,
+            StreamState::Header { header_tokens, .. } => {
+                match self.parse_header_from_tokens(header_tokens, self.next_role.clone()) {
+                    Ok(header) => Some(PartialMessage {
+                        author: Some(header.author),
+                        recipient: header.recipient,
+                        channel: header.channel,
+                        content_type: header.content_type,
+                        content: String::new(),
+                    }),
+                    Err(_) => Some(self.parse_partial_header(header_tokens)),
+                }
+            }
+            StreamState::Content {
+                header,
+                content_tokens,
+            } => Some(PartialMessage {
+                author: Some(header.author.clone()),
+                recipient: header.recipient.clone(),
+                channel: header.channel.clone(),
+                content_type: header.content_type.clone(),
+                content: self.decode_content(content_tokens).unwrap_or_default(),
+            }),
+        }
+    }
+
+    /// Lenient counterpart of `parse_header_from_tokens` for headers that were
+    /// cut off: extracts whatever role, channel and recipient can be read.
+    fn parse_partial_header(&self, header_tokens: &[Rank]) -> PartialMessage {
+        let bytes = self
+            .encoding
+            .tokenizer()
+            .decode_bytes(header_tokens)
+            .unwrap_or_default();
+        let header_string = String::from_utf8_lossy(&bytes);
+        let header_string = header_string.trim_end_matches(char::REPLACEMENT_CHARACTER);
+
+        let mut partial = PartialMessage::default();
+        let mut rest = header_string.to_string();
+        if let Some(channel_marker) = self.encoding.mapped_format_token(FormattingToken::Channel) {
+            if let Some(idx) = rest.find(channel_marker) {
+                let after_marker = &rest[idx + channel_marker.len()..];
+                let channel_end = after_marker
+                    .find(|c: char| c.is_whitespace() || c == '<')
+                    .unwrap_or(after_marker.len());
+                let channel_value = &after_marker[..channel_end];
+                if !channel_value.is_empty() {
+                    partial.channel = Some(channel_value.to_string());
+                }
+                rest = format!("{}{}", &rest[..idx], &after_marker[channel_end..]);
+            }
+        }
+
+        let mut parts = rest.split_ascii_whitespace();
+        partial.author = match &self.next_role {
+            Some(role) => Some(Author::from(role.clone())),
+            None => parts.next().map(|first| match Role::try_from(first) {
+                Ok(role) => Author::from(role),
+                // mirror the strict parser: an unknown leading word names a tool
+                Err(_) => Author::new(Role::Tool, first),
+            }),
+        };
+        partial.recipient = parts
+            .find_map(|part| part.strip_prefix("to="))
+            .map(|recipient| recipient.split('<').next().unwrap_or_default())
+            .filter(|recipient| !recipient.is_empty())
+            .map(str::to_string);
+        partial
+    }
+
     /// Return the textual content of the current message so far.
+    ///
+    /// Bytes of a multi-byte character that has not been completed yet are
+    /// left out until the token completing it arrives.
     pub fn current_content(&self) -> anyhow::Result<String> {
         match &self.state {
-            StreamState::Content { content_tokens, .. } => self
-                .encoding
-                .tokenizer()
-                .decode_utf8(content_tokens)
-                .map_err(|e| anyhow::anyhow!(e)),
+            StreamState::Content { content_tokens, .. } => self.decode_content(content_tokens),
             _ => Ok(String::new()),
         }
     }
 
+    /// Decode content tokens, leaving out the bytes that are still held back.
+    fn decode_content(&self, content_tokens: &[Rank]) -> anyhow::Result<String> {
+        let mut bytes = self.encoding.tokenizer().decode_bytes(content_tokens)?;
+        bytes.truncate(bytes.len().saturating_sub(self.undecoded_bytes.len()));
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Whether the last message has been closed, by `<|end|>`, `<|call|>`,
+    /// `<|return|>` or [`Self::process_eos`], and no header of a following
+    /// message has started yet. `false` before the first message completes.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, StreamState::ExpectStart) && !self.messages.is_empty()
+    }
+
     /// Role of the current message if it has been parsed.
     pub fn current_role(&self) -> Option<Role> {
         match &self.state {
@@ -1341,9 +3342,13 @@ impl StreamableParser {
         }
     }
 
-    /// Decode the last content delta if available.
-    pub fn last_content_delta(&self) -> anyhow::Result<Option<String>> {
-        Ok(self.last_content_delta.clone())
+    /// Text added to the content by the last processed token.
+    ///
+    /// Only complete code points are emitted: a character split across tokens
+    /// shows up in the delta of the token that completes it. The remaining
+    /// bytes are flushed when the message ends.
+    pub fn last_content_delta(&self) -> Option<String> {
+        self.last_content_delta.clone()
     }
 
     /// Consume the parser and return all parsed messages.
@@ -1351,16 +3356,50 @@ impl StreamableParser {
         self.messages
     }
 
+    /// Finalize the stream without a terminating token, e.g. when the
+    /// connection to the model server closed mid-message.
+    ///
+    /// Returns the completed messages together with the unterminated message,
+    /// if any. Unlike [`Self::current_content`], the partial content includes
+    /// the bytes still held back for an incomplete character, decoded lossily.
+    pub fn finish(self) -> (Vec<Message>, Option<PartialMessage>) {
+        let mut partial = self.partial_message();
+        if let (Some(partial), StreamState::Content { content_tokens, .. }) =
+            (partial.as_mut(), &self.state)
+        {
+            if let Ok(bytes) = self.encoding.tokenizer().decode_bytes(content_tokens) {
+                partial.content = String::from_utf8_lossy(&bytes).into_owned();
+            }
+        }
+        (self.messages, partial)
+    }
+
     /// All fully parsed messages so far.
     pub fn messages(&self) -> &[Message] {
         &self.messages
     }
 
-    /// All tokens that were fed into the parser.
+    /// The token that ended each of [`Self::messages`], `None` for a message
+    /// ended by [`Self::process_eos`] or a stop token other than `<|end|>`,
+    /// `<|return|>` and `<|call|>`. Agent loops can stop on
+    /// [`Terminator::Return`] and run the tool on [`Terminator::Call`].
+    pub fn terminators(&self) -> &[Option<Terminator>] {
+        &self.terminators
+    }
+
+    /// All tokens that were fed into the parser, including one that was
+    /// rejected with an error. Feeding them to a fresh parser reproduces the
+    /// same state or error, which makes them a minimal bug report.
     pub fn tokens(&self) -> &[Rank] {
         &self.tokens
     }
 
+    /// The full token buffer processed so far, the same as [`Self::tokens`],
+    /// for dumping alongside a parser bug report.
+    pub fn consumed_tokens(&self) -> &[Rank] {
+        self.tokens()
+    }
+
     /// Expose the current state as a JSON string for Python interop.
     pub fn state_json(&self) -> anyhow::Result<String> {
         #[derive(serde::Serialize)]
@@ -1377,7 +3416,7 @@ impl StreamableParser {
         }
         let serializable = match &self.state {
             StreamState::ExpectStart => SerializableStreamState::ExpectStart,
-            StreamState::Header { header_tokens } => {
+            StreamState::Header { header_tokens, .. } => {
                 SerializableStreamState::Header { header_tokens }
             }
             StreamState::Content {
@@ -1392,32 +3431,301 @@ impl StreamableParser {
     }
 
     /// Return the current recipient if known.
-    pub fn current_recipient(&self) -> Option<String> {
+    ///
+    /// While the header is still streaming this reflects the `to=` segment as
+    /// soon as its value has been terminated.
+    pub fn current_recipient(&self) -> Option<&str> {
         match &self.state {
-            StreamState::Content { header, .. } => header.recipient.clone(),
-            _ => None,
+            StreamState::Header { recipient, .. } => recipient.as_deref(),
+            StreamState::Content { header, .. } => header.recipient.as_deref(),
+            StreamState::ExpectStart => None,
         }
     }
 
     /// Return the current channel if known.
-    pub fn current_channel(&self) -> Option<String> {
+    ///
+    /// While the header is still streaming this reflects the `<|channel|>`
+    /// segment as soon as its value has been terminated.
+    pub fn current_channel(&self) -> Option<&str> {
         match &self.state {
-            StreamState::Content { header, .. } => header.channel.clone(),
-            _ => None,
+            StreamState::Header { channel, .. } => channel.as_deref(),
+            StreamState::Content { header, .. } => header.channel.as_deref(),
+            StreamState::ExpectStart => None,
+        }
+    }
+
+    /// Return the tool call currently being streamed, if any.
+    ///
+    /// Available as soon as a `namespace.name` recipient has been read from
+    /// the header of an assistant message; `arguments` then grows with every
+    /// content token. Use [`PartialToolCall::try_parse_arguments`] to check
+    /// whether they are complete.
+    pub fn current_tool_call(&self) -> Option<PartialToolCall> {
+        if self
+            .current_role()
+            .is_some_and(|role| role != Role::Assistant)
+        {
+            return None;
         }
+        let (namespace, name) = self.current_recipient()?.split_once('.')?;
+        Some(PartialToolCall {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            arguments: self.current_content().ok()?,
+        })
+    }
+
+    /// The top-level fields of the in-flight tool call's JSON arguments that
+    /// are complete so far, see [`PartialToolCall::complete_fields`]. Empty
+    /// when no tool call is being parsed. Nested values are only reported
+    /// once they are closed.
+    pub fn current_json_fields(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.current_tool_call()
+            .map(|call| call.complete_fields())
+            .unwrap_or_default()
+    }
+}
+
+/// Tokens of messages rendered once with [`HarmonyEncoding::render_prefix`]
+/// and reused by [`HarmonyEncoding::render_conversation_with_prefix`].
+#[derive(Clone, Debug)]
+pub struct RenderedPrefix {
+    messages: Vec<Message>,
+    render_options: RenderOptions,
+    tokens: Vec<Rank>,
+}
+
+impl RenderedPrefix {
+    /// The messages this prefix was rendered from.
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// The rendered tokens.
+    pub fn tokens(&self) -> &[Rank] {
+        &self.tokens
     }
 }
 
+/// Which messages [`HarmonyEncoding::truncate_conversation`] drops first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TruncationStrategy {
+    /// Drop the oldest user turn: a user message together with everything up
+    /// to the next user message.
+    DropOldestUserTurns,
+    /// Drop assistant messages on the `analysis` channel, oldest first.
+    DropAnalysisChannels,
+    /// Drop the oldest message that is not a system or developer message.
+    DropOldestPreservingSystem,
+}
+
+/// Result of [`HarmonyEncoding::truncate_conversation`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TruncatedConversation {
+    pub conversation: Conversation,
+    /// Number of messages that were dropped.
+    pub removed_messages: usize,
+    /// Number of tokens saved in the completion render.
+    pub removed_tokens: usize,
+}
+
+/// What the [`RenderConversationConfig`] changed about a render, as
+/// returned by [`HarmonyEncoding::render_conversation_for_completion_reported`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderReport {
+    /// Number of `analysis` messages dropped by
+    /// [`RenderConversationConfig::auto_drop_analysis`].
+    pub dropped_analysis_messages: usize,
+    /// Whether the developer message rendered function tools, which also adds
+    /// the commentary channel line to the system message.
+    pub rendered_function_tools: bool,
+    /// Whether developer tools were left out because of
+    /// [`RenderConversationConfig::suppress_tools`].
+    pub suppressed_tools: bool,
+    /// Whether the `Reasoning:` line of the system message was left out
+    /// because of [`RenderConversationConfig::include_reasoning_effort`].
+    pub suppressed_reasoning_effort: bool,
+    /// Whether [`RenderConversationConfig::extra_system_suffix`] was added to
+    /// a system message.
+    pub added_extra_system_suffix: bool,
+    /// The channel the prompt ends on because of
+    /// [`RenderConversationConfig::force_channel`].
+    pub forced_channel: Option<String>,
+}
+
 // Add config struct for rendering
 #[derive(Clone, Debug)]
 pub struct RenderConversationConfig {
     pub auto_drop_analysis: bool,
+    /// Whether the loss mask of training renders covers assistant messages on
+    /// the `analysis` channel. When `false` reasoning is kept in the prompt
+    /// but masked out of the loss.
+    pub loss_on_analysis: bool,
+    /// Channels messages may be rendered on, `None` to accept any channel.
+    /// Defaults to `analysis`, `commentary` and `final`; rendering without a
+    /// config accepts any channel.
+    pub allowed_channels: Option<Vec<String>>,
+    /// When set, completion prompts end with `<|channel|>{channel}<|message|>`
+    /// so the model has to answer on that channel.
+    pub force_channel: Option<String>,
+    /// Extra text rendered as the last section of the system message's
+    /// [`SystemContent`], e.g. an instruction that should only appear in
+    /// training data. The rendered messages are not modified, and conversations
+    /// without a `SystemContent` system message are unaffected.
+    pub extra_system_suffix: Option<String>,
+    /// Render developer messages without their tools, producing the same
+    /// tokens as a [`crate::chat::DeveloperContent`] that has none, e.g. to
+    /// force a text-only answer for one turn.
+    pub suppress_tools: bool,
+    /// Whether the system message renders the `Reasoning:` line of a
+    /// [`SystemContent`] with a `reasoning_effort`. When `false` the tokens
+    /// are the same as for `reasoning_effort: None`. Defaults to `true`.
+    pub include_reasoning_effort: bool,
+    /// Whether the loss mask of training renders leaves out the header of
+    /// assistant messages, `<|start|>` through `<|message|>`, so only the
+    /// content and the terminator are trained on.
+    pub mask_assistant_header: bool,
 }
 
 impl Default for RenderConversationConfig {
     fn default() -> Self {
         Self {
             auto_drop_analysis: true,
+            loss_on_analysis: true,
+            allowed_channels: Some(
+                ["analysis", "commentary", "final"]
+                    .map(str::to_string)
+                    .to_vec(),
+            ),
+            force_channel: None,
+            extra_system_suffix: None,
+            suppress_tools: false,
+            include_reasoning_effort: true,
+            mask_assistant_header: false,
+        }
+    }
+}
+
+impl RenderConversationConfig {
+    /// Start from [`Self::default`], i.e. render for completion and keep
+    /// analysis only until a final answer, and change options with the
+    /// chainable setters of [`RenderConversationConfigBuilder`].
+    pub fn builder() -> RenderConversationConfigBuilder {
+        RenderConversationConfigBuilder {
+            config: Self::default(),
         }
     }
+
+    /// Set [`Self::auto_drop_analysis`].
+    pub fn with_auto_drop_analysis(mut self, auto_drop_analysis: bool) -> Self {
+        self.auto_drop_analysis = auto_drop_analysis;
+        self
+    }
+    /// Set [`Self::loss_on_analysis`].
+    pub fn with_loss_on_analysis(mut self, loss_on_analysis: bool) -> Self {
+        self.loss_on_analysis = loss_on_analysis;
+        self
+    }
+    /// Restrict messages to these channels, see [`Self::allowed_channels`].
+    pub fn with_allowed_channels<I, T>(mut self, channels: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.allowed_channels = Some(channels.into_iter().map(Into::into).collect());
+        self
+    }
+    /// Accept messages on any channel.
+    pub fn with_any_channel(mut self) -> Self {
+        self.allowed_channels = None;
+        self
+    }
+    /// Answer completion prompts on `channel`, see [`Self::force_channel`].
+    pub fn with_force_channel(mut self, channel: impl Into<String>) -> Self {
+        self.force_channel = Some(channel.into());
+        self
+    }
+    /// Set [`Self::extra_system_suffix`].
+    pub fn with_extra_system_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.extra_system_suffix = Some(suffix.into());
+        self
+    }
+    /// Set [`Self::suppress_tools`].
+    pub fn with_suppress_tools(mut self, suppress_tools: bool) -> Self {
+        self.suppress_tools = suppress_tools;
+        self
+    }
+    /// Set [`Self::include_reasoning_effort`].
+    pub fn with_include_reasoning_effort(mut self, include_reasoning_effort: bool) -> Self {
+        self.include_reasoning_effort = include_reasoning_effort;
+        self
+    }
+    /// Set [`Self::mask_assistant_header`].
+    pub fn with_mask_assistant_header(mut self, mask_assistant_header: bool) -> Self {
+        self.mask_assistant_header = mask_assistant_header;
+        self
+    }
+}
+
+/// Builder returned by [`RenderConversationConfig::builder`]. Each setter
+/// sets the field of the same name.
+#[derive(Clone, Debug)]
+pub struct RenderConversationConfigBuilder {
+    config: RenderConversationConfig,
+}
+
+impl RenderConversationConfigBuilder {
+    pub fn auto_drop_analysis(mut self, auto_drop_analysis: bool) -> Self {
+        self.config.auto_drop_analysis = auto_drop_analysis;
+        self
+    }
+
+    pub fn loss_on_analysis(mut self, loss_on_analysis: bool) -> Self {
+        self.config.loss_on_analysis = loss_on_analysis;
+        self
+    }
+
+    pub fn allowed_channels<I, T>(mut self, channels: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.config = self.config.with_allowed_channels(channels);
+        self
+    }
+
+    /// Accept messages on any channel.
+    pub fn any_channel(mut self) -> Self {
+        self.config.allowed_channels = None;
+        self
+    }
+
+    pub fn force_channel(mut self, channel: impl Into<String>) -> Self {
+        self.config.force_channel = Some(channel.into());
+        self
+    }
+
+    pub fn extra_system_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.config.extra_system_suffix = Some(suffix.into());
+        self
+    }
+
+    pub fn suppress_tools(mut self, suppress_tools: bool) -> Self {
+        self.config.suppress_tools = suppress_tools;
+        self
+    }
+
+    pub fn include_reasoning_effort(mut self, include_reasoning_effort: bool) -> Self {
+        self.config.include_reasoning_effort = include_reasoning_effort;
+        self
+    }
+
+    pub fn mask_assistant_header(mut self, mask_assistant_header: bool) -> Self {
+        self.config.mask_assistant_header = mask_assistant_header;
+        self
+    }
+
+    pub fn build(self) -> RenderConversationConfig {
+        self.config
+    }
 }
@@ -6,13 +6,27 @@ mod registry;
 mod tiktoken;
 pub mod tiktoken_ext;
 
-pub use encoding::{HarmonyEncoding, StreamableParser};
+pub use encoding::{
+    AllowedSpecial, DecodeError, EncodeError, HarmonyEncoding, ParseError, ParseOptions,
+    ParseSystemContentError, PartialMessage, RenderConversationConfig,
+    RenderConversationConfigBuilder, RenderReport, RenderedPrefix, StopConfig, StreamEvent,
+    StreamableParser, Terminator, TruncatedConversation, TruncationStrategy,
+};
 pub use registry::load_harmony_encoding;
-pub use registry::HarmonyEncodingName;
+pub use registry::{detect_encoding, HarmonyEncodingName};
+#[cfg(not(target_arch = "wasm32"))]
+pub use registry::{
+    harmony_cache_dir, harmony_cache_path, load_harmony_encoding_from_file,
+    load_harmony_encoding_from_file_checked, load_harmony_encoding_offline,
+};
+pub use registry::{load_harmony_encoding_from_bytes, load_harmony_encoding_from_reader};
 
 #[cfg(test)]
 pub mod tests;
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
 #[cfg(feature = "python-binding")]
 mod py_module;
 
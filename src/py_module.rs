@@ -111,7 +111,10 @@ impl PyHarmonyEncoding {
                 .get_item("auto_drop_analysis")?
                 .and_then(|v| v.extract().ok())
                 .unwrap_or(true);
-            Some(crate::encoding::RenderConversationConfig { auto_drop_analysis })
+            Some(crate::encoding::RenderConversationConfig {
+                auto_drop_analysis,
+                ..Default::default()
+            })
         } else {
             None
         };
@@ -139,7 +142,10 @@ impl PyHarmonyEncoding {
                 .get_item("auto_drop_analysis")?
                 .and_then(|v| v.extract().ok())
                 .unwrap_or(true);
-            Some(crate::encoding::RenderConversationConfig { auto_drop_analysis })
+            Some(crate::encoding::RenderConversationConfig {
+                auto_drop_analysis,
+                ..Default::default()
+            })
         } else {
             None
         };
@@ -167,7 +173,10 @@ impl PyHarmonyEncoding {
                 .get_item("auto_drop_analysis")?
                 .and_then(|v| v.extract().ok())
                 .unwrap_or(true);
-            Some(crate::encoding::RenderConversationConfig { auto_drop_analysis })
+            Some(crate::encoding::RenderConversationConfig {
+                auto_drop_analysis,
+                ..Default::default()
+            })
         } else {
             None
         };
@@ -177,6 +186,37 @@ impl PyHarmonyEncoding {
             .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
     }
 
+    /// Count the tokens `render_conversation` would produce.
+    fn count_conversation_tokens(
+        &self,
+        conversation_json: &str,
+        config: Option<Bound<'_, PyDict>>,
+    ) -> PyResult<usize> {
+        let conversation: crate::chat::Conversation = serde_json::from_str(conversation_json)
+            .map_err(|e| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                    "invalid conversation JSON: {e}"
+                ))
+            })?;
+
+        let rust_config = if let Some(cfg_dict) = config {
+            let auto_drop_analysis = cfg_dict
+                .get_item("auto_drop_analysis")?
+                .and_then(|v| v.extract().ok())
+                .unwrap_or(true);
+            crate::encoding::RenderConversationConfig {
+                auto_drop_analysis,
+                ..Default::default()
+            }
+        } else {
+            Default::default()
+        };
+
+        self.inner
+            .count_conversation_tokens(&conversation, &rust_config)
+            .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    }
+
     /// Render a single message into tokens.
     fn render(
         &self,
@@ -194,6 +234,7 @@ impl PyHarmonyEncoding {
                 .unwrap_or(false);
             Some(crate::encoding::RenderOptions {
                 conversation_has_function_tools,
+                ..Default::default()
             })
         } else {
             None
@@ -277,20 +318,14 @@ impl PyHarmonyEncoding {
         self.inner.tokenizer().is_special_token(token)
     }
 
-    /// Return the stop tokens for the encoding.
-    fn stop_tokens(&self) -> PyResult<Vec<u32>> {
-        self.inner
-            .stop_tokens()
-            .map(|set| set.into_iter().collect())
-            .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    /// Return the stop tokens for the encoding, sorted by id.
+    fn stop_tokens(&self) -> Vec<u32> {
+        self.inner.stop_token_ids().to_vec()
     }
 
-    /// Return the stop tokens for assistant actions.
-    fn stop_tokens_for_assistant_actions(&self) -> PyResult<Vec<u32>> {
-        self.inner
-            .stop_tokens_for_assistant_actions()
-            .map(|set| set.into_iter().collect())
-            .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    /// Return the stop tokens for assistant actions, sorted by id.
+    fn stop_tokens_for_assistant_actions(&self) -> Vec<u32> {
+        self.inner.stop_token_ids_for_assistant_actions().to_vec()
     }
 }
 
@@ -324,6 +359,10 @@ impl PyStreamableParser {
             .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
     }
 
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
     #[getter]
     fn current_content(&self) -> PyResult<String> {
         self.inner
@@ -342,10 +381,8 @@ impl PyStreamableParser {
     }
 
     #[getter]
-    fn last_content_delta(&self) -> PyResult<Option<String>> {
-        self.inner
-            .last_content_delta()
-            .map_err(|e| PyErr::new::<HarmonyError, _>(e.to_string()))
+    fn last_content_delta(&self) -> Option<String> {
+        self.inner.last_content_delta()
     }
 
     #[getter]
@@ -360,6 +397,11 @@ impl PyStreamableParser {
         self.inner.tokens().to_vec()
     }
 
+    #[getter]
+    fn consumed_tokens(&self) -> Vec<u32> {
+        self.inner.consumed_tokens().to_vec()
+    }
+
     #[getter]
     fn state(&self) -> PyResult<String> {
         self.inner
@@ -369,12 +411,17 @@ impl PyStreamableParser {
 
     #[getter]
     fn current_recipient(&self) -> Option<String> {
-        self.inner.current_recipient()
+        self.inner.current_recipient().map(str::to_string)
     }
 
     #[getter]
     fn current_channel(&self) -> Option<String> {
-        self.inner.current_channel()
+        self.inner.current_channel().map(str::to_string)
+    }
+
+    #[getter]
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
     }
 }
 
@@ -5,6 +5,7 @@ use std::{
 
 use crate::{
     encoding::{FormattingToken, HarmonyEncoding},
+    tiktoken::{CoreBPE, Rank},
     tiktoken_ext,
 };
 
@@ -13,24 +14,32 @@ pub enum HarmonyEncodingName {
     HarmonyGptOss,
 }
 
+impl HarmonyEncodingName {
+    /// Every encoding the registry can load.
+    pub fn all() -> &'static [Self] {
+        &[Self::HarmonyGptOss]
+    }
+
+    /// The name [`std::str::FromStr`] accepts, e.g. `"HarmonyGptOss"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HarmonyEncodingName::HarmonyGptOss => "HarmonyGptOss",
+        }
+    }
+}
+
 impl std::fmt::Display for HarmonyEncodingName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                HarmonyEncodingName::HarmonyGptOss => "HarmonyGptOss",
-            }
-        )
+        f.write_str(self.as_str())
     }
 }
 
 impl std::str::FromStr for HarmonyEncodingName {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "HarmonyGptOss" => Ok(HarmonyEncodingName::HarmonyGptOss),
-            _ => anyhow::bail!("Invalid HarmonyEncodingName: {}", s),
+        match Self::all().iter().find(|name| name.as_str() == s) {
+            Some(name) => Ok(*name),
+            None => anyhow::bail!("Invalid HarmonyEncodingName: {}", s),
         }
     }
 }
@@ -43,55 +52,157 @@ impl std::fmt::Debug for HarmonyEncodingName {
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<HarmonyEncoding> {
-    match name {
-        HarmonyEncodingName::HarmonyGptOss => {
-            let n_ctx = 1_048_576; // 2^20
-            let max_action_length = 524_288; // 2^19
-            let encoding_ext = tiktoken_ext::Encoding::O200kHarmony;
-            Ok(HarmonyEncoding {
-                name: name.to_string(),
-                n_ctx,
-                tokenizer: Arc::new(encoding_ext.load()?),
-                tokenizer_name: encoding_ext.name().to_owned(),
-                max_message_tokens: n_ctx - max_action_length,
-                max_action_length,
-                format_token_mapping: make_mapping([
-                    (FormattingToken::Start, "<|start|>"),
-                    (FormattingToken::Message, "<|message|>"),
-                    (FormattingToken::EndMessage, "<|end|>"),
-                    (FormattingToken::EndMessageDoneSampling, "<|return|>"),
-                    (FormattingToken::Refusal, "<|refusal|>"),
-                    (FormattingToken::ConstrainedFormat, "<|constrain|>"),
-                    (FormattingToken::Channel, "<|channel|>"),
-                    (FormattingToken::EndMessageAssistantToTool, "<|call|>"),
-                    (FormattingToken::BeginUntrusted, "<|untrusted|>"),
-                    (FormattingToken::EndUntrusted, "<|end_untrusted|>"),
-                ]),
-                stop_formatting_tokens: HashSet::from([
-                    FormattingToken::EndMessageDoneSampling,
-                    FormattingToken::EndMessageAssistantToTool,
-                    FormattingToken::EndMessage,
-                ]),
-                stop_formatting_tokens_for_assistant_actions: HashSet::from([
-                    FormattingToken::EndMessageDoneSampling,
-                    FormattingToken::EndMessageAssistantToTool,
-                ]),
-            })
-        }
-    }
+    let encoding_ext = tiktoken_encoding(name);
+    build_harmony_encoding(name, encoding_ext, encoding_ext.load()?)
+}
+
+/// Like [`load_harmony_encoding`], but never downloads the vocab file.
+///
+/// The vocab is looked up in `TIKTOKEN_ENCODINGS_BASE` or the local cache
+/// directory, exactly as [`load_harmony_encoding`] would. If it is not there,
+/// this fails with [`tiktoken_ext::LoadError::OfflineCacheMiss`], which names
+/// the expected cache path.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_harmony_encoding_offline(name: HarmonyEncodingName) -> anyhow::Result<HarmonyEncoding> {
+    let encoding_ext = tiktoken_encoding(name);
+    build_harmony_encoding(name, encoding_ext, encoding_ext.load_offline()?)
+}
+
+/// The directory [`load_harmony_encoding`] caches downloaded vocab files in.
+/// Honors `TIKTOKEN_RS_CACHE_DIR`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn harmony_cache_dir() -> std::path::PathBuf {
+    tiktoken_ext::cache_dir()
+}
+
+/// The path [`load_harmony_encoding`] reads the vocab file for `name` from.
+/// Honors `TIKTOKEN_ENCODINGS_BASE` and `TIKTOKEN_RS_CACHE_DIR`. Placing the
+/// vocab file here ahead of time lets later loads run without network access.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn harmony_cache_path(name: HarmonyEncodingName) -> std::path::PathBuf {
+    tiktoken_encoding(name).vocab_file_path()
 }
 
 #[cfg(target_arch = "wasm32")]
 pub async fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<HarmonyEncoding> {
+    let encoding_ext = tiktoken_encoding(name);
+    build_harmony_encoding(name, encoding_ext, encoding_ext.load().await?)
+}
+
+/// Load a harmony encoding from a tiktoken vocab file on disk instead of
+/// downloading it.
+///
+/// `encoding_name` is parsed as a [`HarmonyEncodingName`].
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_harmony_encoding_from_file<P>(
+    path: P,
+    encoding_name: &str,
+) -> anyhow::Result<HarmonyEncoding>
+where
+    P: AsRef<std::path::Path>,
+{
+    let file = std::fs::File::open(path)?;
+    load_harmony_encoding_from_reader(std::io::BufReader::new(file), encoding_name)
+}
+
+/// Like [`load_harmony_encoding_from_file`], but fails with
+/// [`tiktoken_ext::LoadError::ChecksumMismatch`] unless the file hashes to
+/// `expected_sha256`, e.g. [`tiktoken_ext::O200K_HARMONY_SHA256`].
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_harmony_encoding_from_file_checked<P>(
+    path: P,
+    encoding_name: &str,
+    expected_sha256: &str,
+) -> anyhow::Result<HarmonyEncoding>
+where
+    P: AsRef<std::path::Path>,
+{
+    let name: HarmonyEncodingName = encoding_name.parse()?;
+    let encoding_ext = tiktoken_encoding(name);
+    let file = std::fs::File::open(path)?;
+    let tokenizer = encoding_ext.load_from_reader_checked(file, expected_sha256)?;
+    build_harmony_encoding(name, encoding_ext, tokenizer)
+}
+
+/// Load a harmony encoding from a reader yielding the contents of a tiktoken
+/// vocab file.
+///
+/// `encoding_name` is parsed as a [`HarmonyEncodingName`].
+pub fn load_harmony_encoding_from_reader<R>(
+    reader: R,
+    encoding_name: &str,
+) -> anyhow::Result<HarmonyEncoding>
+where
+    R: std::io::Read,
+{
+    let name: HarmonyEncodingName = encoding_name.parse()?;
+    let encoding_ext = tiktoken_encoding(name);
+    build_harmony_encoding(name, encoding_ext, encoding_ext.load_from_reader(reader)?)
+}
+
+/// Load a harmony encoding from the in-memory contents of a tiktoken vocab
+/// file, e.g. one embedded with `include_bytes!`.
+///
+/// `encoding_name` is parsed as a [`HarmonyEncodingName`].
+pub fn load_harmony_encoding_from_bytes(
+    bytes: &[u8],
+    encoding_name: &str,
+) -> anyhow::Result<HarmonyEncoding> {
+    load_harmony_encoding_from_reader(bytes, encoding_name)
+}
+
+/// Guess which harmony encoding produced `tokens`.
+///
+/// This is a heuristic: it returns [`HarmonyEncodingName::HarmonyGptOss`]
+/// if the tokens contain one of the harmony formatting tokens such as
+/// `<|start|>` or `<|message|>`, which `o200k_base` only knows as reserved
+/// ids. Plain text tokenizes identically under `o200k_base` and
+/// `o200k_harmony`, so tokens without any formatting token are ambiguous and
+/// yield `None`, as do tokens outside the `o200k` vocabulary.
+pub fn detect_encoding(tokens: &[Rank]) -> Option<HarmonyEncodingName> {
+    let harmony = tiktoken_ext::Encoding::O200kHarmony;
+    let max_rank = harmony
+        .all_special_tokens()
+        .iter()
+        .map(|(_, rank)| *rank)
+        .max()?;
+    if tokens.iter().any(|&token| token > max_rank) {
+        return None;
+    }
+    let formatting_tokens: HashSet<Rank> = harmony
+        .special_tokens()
+        .iter()
+        .filter(|(token, _)| {
+            !token.starts_with("<|reserved_")
+                && !matches!(*token, "<|startoftext|>" | "<|endoftext|>")
+        })
+        .map(|(_, rank)| *rank)
+        .collect();
+    tokens
+        .iter()
+        .any(|token| formatting_tokens.contains(token))
+        .then_some(HarmonyEncodingName::HarmonyGptOss)
+}
+
+fn tiktoken_encoding(name: HarmonyEncodingName) -> tiktoken_ext::Encoding {
     match name {
+        HarmonyEncodingName::HarmonyGptOss => tiktoken_ext::Encoding::O200kHarmony,
+    }
+}
+
+fn build_harmony_encoding(
+    name: HarmonyEncodingName,
+    encoding_ext: tiktoken_ext::Encoding,
+    tokenizer: CoreBPE,
+) -> anyhow::Result<HarmonyEncoding> {
+    let mut encoding = match name {
         HarmonyEncodingName::HarmonyGptOss => {
             let n_ctx = 1_048_576; // 2^20
             let max_action_length = 524_288; // 2^19
-            let encoding_ext = tiktoken_ext::Encoding::O200kHarmony;
-            Ok(HarmonyEncoding {
+            HarmonyEncoding {
                 name: name.to_string(),
                 n_ctx,
-                tokenizer: Arc::new(encoding_ext.load().await?),
+                tokenizer: Arc::new(tokenizer),
                 tokenizer_name: encoding_ext.name().to_owned(),
                 max_message_tokens: n_ctx - max_action_length,
                 max_action_length,
@@ -116,10 +227,13 @@ pub async fn load_harmony_encoding(name: HarmonyEncodingName) -> anyhow::Result<
                     FormattingToken::EndMessageDoneSampling,
                     FormattingToken::EndMessageAssistantToTool,
                 ]),
-                conversation_has_function_tools: Arc::new(AtomicBool::new(false)),
-            })
+                additional_special_tokens: Vec::new(),
+                token_ids: Default::default(),
+            }
         }
-    }
+    };
+    encoding.token_ids = encoding.resolve_formatting_token_ids()?;
+    Ok(encoding)
 }
 
 fn make_mapping<I>(iter: I) -> HashMap<FormattingToken, String>
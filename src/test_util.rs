@@ -0,0 +1,56 @@
+//! Helpers for tests of code that renders or parses harmony conversations.
+//!
+//! Enabled with the `test-util` feature.
+
+use crate::{
+    chat::{Content, Conversation, Message, Role, TextContent},
+    HarmonyEncoding,
+};
+
+/// Render `conversation` for training, parse the tokens back and assert that
+/// the parsed messages match the original ones.
+///
+/// Differences that rendering cannot preserve are ignored: text contents are
+/// compared concatenated, author names only count for tool messages, and the
+/// content of system and developer messages is not compared since it parses
+/// back as plain text.
+///
+/// # Panics
+///
+/// Panics if rendering or parsing fails, or if the messages differ.
+pub fn assert_render_parse_roundtrip(encoding: &HarmonyEncoding, conversation: &Conversation) {
+    let tokens = encoding
+        .render_conversation_for_training(conversation, None)
+        .expect("failed to render conversation");
+    let parsed = encoding
+        .parse_messages_from_completion_tokens(tokens, None)
+        .expect("failed to parse rendered conversation");
+    let expected: Vec<Message> = conversation.messages.iter().map(normalize).collect();
+    let actual: Vec<Message> = parsed.iter().map(normalize).collect();
+    assert_eq!(
+        actual, expected,
+        "conversation did not survive render and parse"
+    );
+}
+
+fn normalize(message: &Message) -> Message {
+    let mut message = message.clone();
+    if message.author.role != Role::Tool {
+        message.author.name = None;
+    }
+    message.content = match message.author.role {
+        Role::System | Role::Developer => Vec::new(),
+        _ => {
+            let text = message
+                .content
+                .iter()
+                .map(|content| match content {
+                    Content::Text(TextContent { text }) => text.as_str(),
+                    _ => panic!("unexpected non-text content in {message:?}"),
+                })
+                .collect();
+            vec![Content::Text(TextContent { text })]
+        }
+    };
+    message
+}
@@ -169,6 +169,8 @@ pub struct CoreBPE {
     regex_tls: Vec<Regex>,
     special_regex_tls: Vec<Regex>,
     sorted_token_bytes: Vec<Vec<u8>>,
+    /// Length in bytes of the longest ordinary token.
+    max_token_len: usize,
 }
 
 impl CoreBPE {
@@ -219,24 +221,66 @@ impl CoreBPE {
     }
 
     pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
+        let mut ret = vec![];
+        self.encode_ordinary_into(text, &mut ret);
+        ret
+    }
+
+    /// Like [`Self::encode_ordinary`], but gives up and returns `None` as soon
+    /// as the text takes more than `max_tokens` tokens. A regex piece is not
+    /// encoded if it cannot fit in the remaining budget, since every token
+    /// covers at most the longest token's bytes, so a single long word is
+    /// rejected without running BPE on it.
+    pub fn encode_ordinary_with_limit(&self, text: &str, max_tokens: usize) -> Option<Vec<Rank>> {
+        let mut ret = vec![];
+        self.encode_ordinary_into_limited(text, &mut ret, max_tokens)
+            .then_some(ret)
+    }
+
+    /// Like [`Self::encode_ordinary`], but appends to `ret`.
+    pub fn encode_ordinary_into(&self, text: &str, ret: &mut Vec<Rank>) {
+        self.encode_ordinary_into_limited(text, ret, usize::MAX);
+    }
+
+    /// Appends the tokens of `text` to `ret`, returning `false` without
+    /// finishing once `ret` would hold more than `limit` tokens.
+    fn encode_ordinary_into_limited(&self, text: &str, ret: &mut Vec<Rank>, limit: usize) -> bool {
         // This is the core of the encoding logic; the other functions in here
         // just make things complicated :-)
         let regex = self._get_tl_regex();
-        let mut ret = vec![];
         for mat in regex.find_iter(text) {
             let piece = mat.unwrap().as_str().as_bytes();
+            let budget = limit.saturating_sub(ret.len());
+            if piece.len().div_ceil(self.max_token_len) > budget {
+                return false;
+            }
             match self.encoder.get(piece) {
                 Some(token) => ret.push(*token),
                 None => ret.extend(&byte_pair_encode(piece, &self.encoder)),
             }
+            if ret.len() > limit {
+                return false;
+            }
         }
-        ret
+        true
     }
 
     pub fn encode(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<Rank>, usize) {
+        let mut ret = vec![];
+        let last_piece_token_len = self.encode_into(text, allowed_special, &mut ret);
+        (ret, last_piece_token_len)
+    }
+
+    /// Like [`Self::encode`], but appends to `ret` and only returns the
+    /// length of the last piece.
+    pub fn encode_into(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        ret: &mut Vec<Rank>,
+    ) -> usize {
         let special_regex = self._get_tl_special_regex();
         let regex = self._get_tl_regex();
-        let mut ret = vec![];
 
         let mut start = 0;
         let mut last_piece_token_len = 0;
@@ -286,7 +330,7 @@ impl CoreBPE {
 
         // last_piece_token_len is how many tokens came from the last regex split. This is used
         // for determining unstable tokens, since you can't merge across (stable) regex splits
-        (ret, last_piece_token_len)
+        last_piece_token_len
     }
 
     fn _increase_last_piece_token_len(
@@ -493,6 +537,7 @@ impl CoreBPE {
         // Clone because I don't know how to tell Rust I'm not going to change the map
         let mut sorted_token_bytes: Vec<Vec<u8>> = encoder.keys().cloned().collect();
         sorted_token_bytes.sort();
+        let max_token_len = encoder.keys().map(Vec::len).max().unwrap_or(1);
 
         Ok(Self {
             encoder,
@@ -504,9 +549,51 @@ impl CoreBPE {
                 .map(|_| special_regex.clone())
                 .collect(),
             sorted_token_bytes,
+            max_token_len,
         })
     }
 
+    /// Rebuild this tokenizer with extra special tokens.
+    ///
+    /// An id may only be claimed if it is unused or currently held by a
+    /// `<|reserved_{id}|>` placeholder, which is then replaced.
+    pub fn with_additional_special_tokens<SE>(
+        &self,
+        special_tokens: SE,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>>
+    where
+        SE: IntoIterator<Item = (String, Rank)>,
+    {
+        let mut special_tokens_encoder = self.special_tokens_encoder.clone();
+        for (token, rank) in special_tokens {
+            if self.decoder.contains_key(&rank) {
+                return Err(
+                    format!("id {rank} of {token} is already used by a regular token").into(),
+                );
+            }
+            if let Some(existing) = special_tokens_encoder.get(&token) {
+                return Err(format!("{token} is already registered with id {existing}").into());
+            }
+            let placeholder = format!("<|reserved_{rank}|>");
+            if let Some((existing, _)) =
+                special_tokens_encoder
+                    .iter()
+                    .find(|(existing, existing_rank)| {
+                        **existing_rank == rank && **existing != placeholder
+                    })
+            {
+                return Err(format!("id {rank} of {token} is already used by {existing}").into());
+            }
+            special_tokens_encoder.remove(&placeholder);
+            special_tokens_encoder.insert(token, rank);
+        }
+        Self::new_internal(
+            self.encoder.clone(),
+            special_tokens_encoder,
+            self._get_tl_regex().as_str(),
+        )
+    }
+
     pub fn special_tokens(&self) -> HashSet<&str> {
         self.special_tokens_encoder
             .keys()
@@ -522,4 +609,46 @@ impl CoreBPE {
     pub fn is_special_token(&self, token: Rank) -> bool {
         self.special_tokens_decoder.contains_key(&token)
     }
+
+    /// The regex used to split text before applying BPE.
+    pub fn pattern(&self) -> &str {
+        self._get_tl_regex().as_str()
+    }
+
+    /// Number of distinct token ids, special tokens included.
+    pub fn vocab_size(&self) -> usize {
+        self.decoder.len() + self.special_tokens_decoder.len()
+    }
+
+    /// SHA-256 over the ranks and bytes of all tokens, the special tokens
+    /// and the split pattern, as 64 hex digits. Independent of hash map
+    /// order, so it is stable across processes and platforms.
+    pub fn fingerprint(&self) -> String {
+        use sha2::{Digest as _, Sha256};
+
+        fn add_tokens<'a>(hasher: &mut Sha256, tokens: impl Iterator<Item = (&'a Rank, &'a [u8])>) {
+            let mut tokens: Vec<_> = tokens.collect();
+            tokens.sort_unstable();
+            hasher.update((tokens.len() as u64).to_le_bytes());
+            for (rank, bytes) in tokens {
+                hasher.update(rank.to_le_bytes());
+                hasher.update((bytes.len() as u64).to_le_bytes());
+                hasher.update(bytes);
+            }
+        }
+
+        let mut hasher = Sha256::new();
+        add_tokens(
+            &mut hasher,
+            self.decoder.iter().map(|(rank, bytes)| (rank, &bytes[..])),
+        );
+        add_tokens(
+            &mut hasher,
+            self.special_tokens_decoder
+                .iter()
+                .map(|(rank, bytes)| (rank, &bytes[..])),
+        );
+        hasher.update(self.pattern().as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
 }
@@ -1,2 +1,4 @@
 mod public_encodings;
-pub use public_encodings::{set_tiktoken_base_url, Encoding};
+pub use public_encodings::{
+    cache_dir, set_tiktoken_base_url, Encoding, LoadError, O200K_BASE_SHA256, O200K_HARMONY_SHA256,
+};
@@ -35,6 +35,15 @@ pub enum LoadError {
 
     #[error("failed to extend encoding")]
     FailedToExtendEncoding(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("vocab file checksum mismatch: expected sha256 {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error("special token {token} has rank {rank}, which the vocab file already assigns")]
+    SpecialTokenCollision { token: String, rank: Rank },
+
+    #[error("vocab file {url} is not in the local cache (expected at {path:?}) and downloading is disabled")]
+    OfflineCacheMiss { url: String, path: PathBuf },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -53,7 +62,16 @@ pub enum RemoteVocabFileError {
     },
 }
 
+/// SHA-256 of the official `o200k_base.tiktoken` vocab file.
+pub const O200K_BASE_SHA256: &str =
+    "446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d";
+
+/// SHA-256 of the vocab file of `o200k_harmony`, which uses the
+/// `o200k_base.tiktoken` vocab.
+pub const O200K_HARMONY_SHA256: &str = O200K_BASE_SHA256;
+
 const TIKTOKEN_ENCODINGS_BASE_VAR: &str = "TIKTOKEN_ENCODINGS_BASE";
+const TIKTOKEN_RS_CACHE_DIR_VAR: &str = "TIKTOKEN_RS_CACHE_DIR";
 const DEFAULT_TIKTOKEN_BASE_URL: &str = "https://openaipublic.blob.core.windows.net/encodings/";
 
 static TIKTOKEN_BASE_URL_OVERRIDE: OnceLock<String> = OnceLock::new();
@@ -123,83 +141,51 @@ impl Encoding {
 
     #[cfg(not(target_arch = "wasm32"))]
     pub fn load(&self) -> Result<CoreBPE, LoadError> {
-        #[cfg(not(target_arch = "wasm32"))]
+        self.load_from_local_or_remote(true)
+    }
+
+    /// Like [`Self::load`], but only looks in `TIKTOKEN_ENCODINGS_BASE` and the
+    /// local cache. Fails with [`LoadError::OfflineCacheMiss`] instead of
+    /// downloading the vocab file.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load_offline(&self) -> Result<CoreBPE, LoadError> {
+        self.load_from_local_or_remote(false)
+    }
+
+    /// The path [`Self::load`] reads the vocab file from: the file in
+    /// `TIKTOKEN_ENCODINGS_BASE` if that is set, otherwise its entry in
+    /// [`cache_dir`]. The file does not have to exist yet.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn vocab_file_path(&self) -> PathBuf {
+        if let Ok(base_dir) = std::env::var(TIKTOKEN_ENCODINGS_BASE_VAR) {
+            PathBuf::from(base_dir).join(self.vocab_file_name())
+        } else {
+            resolve_cache_path(&cache_dir(), &self.public_vocab_file_url())
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_from_local_or_remote(&self, allow_download: bool) -> Result<CoreBPE, LoadError> {
         let (vocab_file_path, check_hash) =
             if let Ok(base_dir) = std::env::var(TIKTOKEN_ENCODINGS_BASE_VAR) {
                 (PathBuf::from(base_dir).join(self.vocab_file_name()), true)
             } else {
                 let url = self.public_vocab_file_url();
-                (
+                let path = if allow_download {
                     download_or_find_cached_file(&url, Some(self.expected_hash()))
-                        .map_err(LoadError::DownloadOrLoadVocabFile)?,
-                    false,
-                )
+                        .map_err(LoadError::DownloadOrLoadVocabFile)?
+                } else {
+                    find_cached_file(&resolve_cache_dir()?, &url, Some(self.expected_hash()))?
+                };
+                (path, false)
             };
 
-        match self {
-            Self::O200kHarmony => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((200014..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        specials,
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-                }
-            }
-            Self::O200kBase => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((199998..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        specials,
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-                }
-            }
-            _ => {
-                #[cfg(not(target_arch = "wasm32"))]
-                {
-                    load_encoding_from_file(
-                        vocab_file_path,
-                        check_hash.then(|| self.expected_hash()),
-                        self.special_tokens().iter().cloned(),
-                        &self.pattern(),
-                    )
-                }
-                #[cfg(target_arch = "wasm32")]
-                {
-                    load_encoding_from_bytes(
-                        &vocab_bytes,
-                        None,
-                        self.special_tokens().iter().cloned(),
-                        &self.pattern(),
-                    )
-                }
-            }
-        }
+        load_encoding_from_file(
+            vocab_file_path,
+            check_hash.then(|| self.expected_hash()),
+            self.all_special_tokens(),
+            &self.pattern(),
+        )
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -209,32 +195,40 @@ impl Encoding {
             .await
             .map_err(LoadError::DownloadOrLoadVocabFile)?;
 
-        match self {
-            Self::O200kHarmony => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((200014..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-            }
-            Self::O200kBase => {
-                let mut specials: Vec<(String, Rank)> = self
-                    .special_tokens()
-                    .iter()
-                    .map(|(s, r)| ((*s).to_string(), *r))
-                    .collect();
-                specials.extend((199998..=201088).map(|id| (format!("<|reserved_{id}|>"), id)));
-                load_encoding_from_bytes(&vocab_bytes, None, specials, &self.pattern())
-            }
-            _ => load_encoding_from_bytes(
-                &vocab_bytes,
-                None,
-                self.special_tokens().iter().cloned(),
-                &self.pattern(),
-            ),
-        }
+        load_encoding_from_reader(
+            vocab_bytes.as_slice(),
+            None,
+            self.all_special_tokens(),
+            &self.pattern(),
+        )
+    }
+
+    /// Build this encoding from a reader yielding the contents of its tiktoken
+    /// vocab file. The contents are not checked against the expected hash.
+    pub fn load_from_reader<R>(&self, reader: R) -> Result<CoreBPE, LoadError>
+    where
+        R: std::io::Read,
+    {
+        load_encoding_from_reader(reader, None, self.all_special_tokens(), &self.pattern())
+    }
+
+    /// Like [`Self::load_from_reader`], but fails with
+    /// [`LoadError::ChecksumMismatch`] unless the contents hash to
+    /// `expected_sha256` (hex encoded).
+    pub fn load_from_reader_checked<R>(
+        &self,
+        reader: R,
+        expected_sha256: &str,
+    ) -> Result<CoreBPE, LoadError>
+    where
+        R: std::io::Read,
+    {
+        load_encoding_from_reader(
+            reader,
+            Some(expected_sha256),
+            self.all_special_tokens(),
+            &self.pattern(),
+        )
     }
 
     fn public_vocab_file_url(&self) -> String {
@@ -256,15 +250,13 @@ impl Encoding {
 
     fn expected_hash(&self) -> &'static str {
         match self {
-            Self::O200kBase => "446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d",
-            Self::O200kHarmony => {
-                "446a9538cb6c348e3516120d7c08b09f57c36495e2acfffe59a5bf8b0cfb1a2d"
-            }
+            Self::O200kBase => O200K_BASE_SHA256,
+            Self::O200kHarmony => O200K_HARMONY_SHA256,
             Self::Cl100kBase => "223921b76ee99bde995b7ff738513eef100fb51d18c93597a113bcffe865b2a7",
         }
     }
 
-    fn special_tokens(&self) -> &'static [(&'static str, Rank)] {
+    pub(crate) fn special_tokens(&self) -> &'static [(&'static str, Rank)] {
         match self {
             Self::O200kBase => &[],
             Self::O200kHarmony => &[
@@ -295,6 +287,25 @@ impl Encoding {
         }
     }
 
+    /// The special tokens of this encoding, including the reserved ranges
+    /// that are not listed individually in [`Self::special_tokens`].
+    pub(crate) fn all_special_tokens(&self) -> Vec<(String, Rank)> {
+        let mut specials: Vec<(String, Rank)> = self
+            .special_tokens()
+            .iter()
+            .map(|(s, r)| ((*s).to_string(), *r))
+            .collect();
+        let reserved = match self {
+            Self::O200kHarmony => Some(200014..=201088),
+            Self::O200kBase => Some(199998..=201088),
+            Self::Cl100kBase => None,
+        };
+        if let Some(reserved) = reserved {
+            specials.extend(reserved.map(|id| (format!("<|reserved_{id}|>"), id)));
+        }
+        specials
+    }
+
     fn pattern(&self) -> String {
         match self {
             Self::O200kBase => {
@@ -327,13 +338,38 @@ impl Encoding {
 }
 
 fn load_tiktoken_vocab<R>(
-    mut reader: R,
+    reader: R,
     expected_hash: Option<&str>,
-) -> std::result::Result<HashMap<Vec<u8>, Rank>, std::io::Error>
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError>
 where
     R: std::io::BufRead,
 {
-    let mut hasher = expected_hash.map(|_| Sha256::new());
+    let (bpe_ranks, computed_hash) = read_tiktoken_vocab(reader, expected_hash.is_some())
+        .map_err(LoadError::InvalidTiktokenVocabFile)?;
+    if let (Some(expected), Some(actual)) = (expected_hash, computed_hash) {
+        if actual != expected {
+            return Err(LoadError::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    Ok(bpe_ranks)
+}
+
+/// Parsed ranks plus the hex SHA-256 of the file, when it was computed.
+type VocabWithHash = (HashMap<Vec<u8>, Rank>, Option<String>);
+
+/// Parse a tiktoken vocab, also returning the SHA-256 of the contents if
+/// `compute_hash` is set.
+fn read_tiktoken_vocab<R>(
+    mut reader: R,
+    compute_hash: bool,
+) -> std::result::Result<VocabWithHash, std::io::Error>
+where
+    R: std::io::BufRead,
+{
+    let mut hasher = compute_hash.then(Sha256::new);
     let mut bpe_ranks = HashMap::new();
     // using readline here so that the line returned includes the newline bytes for the hasher
     let mut lin_no = 0;
@@ -365,27 +401,18 @@ where
         bpe_ranks.insert(bytes, rank);
         line_buffer.clear();
     }
-    if let Some(hasher) = hasher {
-        let expected_hash = expected_hash.unwrap();
-        let computed_hash = format!("{:x}", hasher.finalize());
-        if computed_hash != expected_hash {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("hash mismatch: computed={computed_hash}, expected={expected_hash}"),
-            ));
-        }
-    }
-    Ok(bpe_ranks)
+    let computed_hash = hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+    Ok((bpe_ranks, computed_hash))
 }
 
 pub fn load_tiktoken_vocab_file<P>(
     path: P,
     expected_hash: Option<&str>,
-) -> std::result::Result<HashMap<Vec<u8>, Rank>, std::io::Error>
+) -> Result<HashMap<Vec<u8>, Rank>, LoadError>
 where
     P: AsRef<Path>,
 {
-    let file = std::fs::File::open(path)?;
+    let file = std::fs::File::open(path).map_err(LoadError::InvalidTiktokenVocabFile)?;
     let reader = std::io::BufReader::new(file);
     load_tiktoken_vocab(reader, expected_hash)
 }
@@ -401,14 +428,58 @@ where
     S: IntoIterator<Item = (TS, Rank)>,
     TS: Into<String>,
 {
-    let encoder = load_tiktoken_vocab_file(file_path, expected_hash)
-        .map_err(LoadError::InvalidTiktokenVocabFile)?;
-    CoreBPE::new(
-        encoder,
-        special_tokens.into_iter().map(|(k, v)| (k.into(), v)),
-        pattern,
-    )
-    .map_err(LoadError::CoreBPECreationFailed)
+    let encoder = load_tiktoken_vocab_file(file_path, expected_hash)?;
+    new_core_bpe(encoder, special_tokens, pattern)
+}
+
+pub fn load_encoding_from_reader<R, S, TS>(
+    reader: R,
+    expected_hash: Option<&str>,
+    special_tokens: S,
+    pattern: &str,
+) -> Result<CoreBPE, LoadError>
+where
+    R: std::io::Read,
+    S: IntoIterator<Item = (TS, Rank)>,
+    TS: Into<String>,
+{
+    let encoder = load_tiktoken_vocab(BufReader::new(reader), expected_hash)?;
+    new_core_bpe(encoder, special_tokens, pattern)
+}
+
+/// Build a [`CoreBPE`], rejecting special tokens whose rank is already used
+/// by the vocab. `CoreBPE` accepts such overlaps silently, and parsing then
+/// breaks much later.
+fn new_core_bpe<S, TS>(
+    encoder: HashMap<Vec<u8>, Rank>,
+    special_tokens: S,
+    pattern: &str,
+) -> Result<CoreBPE, LoadError>
+where
+    S: IntoIterator<Item = (TS, Rank)>,
+    TS: Into<String>,
+{
+    let special_tokens: HashMap<String, Rank> = special_tokens
+        .into_iter()
+        .map(|(k, v)| (k.into(), v))
+        .collect();
+    let special_ranks: HashMap<Rank, &str> = special_tokens
+        .iter()
+        .map(|(token, rank)| (*rank, token.as_str()))
+        .collect();
+    // report the lowest colliding rank so the error does not depend on
+    // hash map order
+    if let Some(rank) = encoder
+        .values()
+        .filter(|rank| special_ranks.contains_key(rank))
+        .min()
+    {
+        return Err(LoadError::SpecialTokenCollision {
+            token: special_ranks[rank].to_string(),
+            rank: *rank,
+        });
+    }
+    CoreBPE::new(encoder, special_tokens, pattern).map_err(LoadError::CoreBPECreationFailed)
 }
 
 /// This returns the path to a file containing the data at `url`. If the file is
@@ -440,6 +511,24 @@ fn download_or_find_cached_file(
     Ok(cache_path)
 }
 
+/// This returns the path to the cached copy of `url` in `cache_dir`, without
+/// downloading it. A cached file that fails the hash check counts as missing.
+#[cfg(not(target_arch = "wasm32"))]
+fn find_cached_file(
+    cache_dir: &Path,
+    url: &str,
+    expected_hash: Option<&str>,
+) -> Result<PathBuf, LoadError> {
+    let cache_path = resolve_cache_path(cache_dir, url);
+    if cache_path.exists() && verify_file_hash(&cache_path, expected_hash)? {
+        return Ok(cache_path);
+    }
+    Err(LoadError::OfflineCacheMiss {
+        url: url.to_string(),
+        path: cache_path,
+    })
+}
+
 #[cfg(target_arch = "wasm32")]
 async fn download_or_find_cached_file_bytes(
     url: &str,
@@ -459,23 +548,30 @@ async fn download_or_find_cached_file_bytes(
     Ok(bytes)
 }
 
-fn resolve_cache_dir() -> Result<PathBuf, RemoteVocabFileError> {
+/// The directory downloaded vocab files are cached in:
+/// `TIKTOKEN_RS_CACHE_DIR` if set, otherwise `tiktoken-rs-cache` in the system
+/// temp dir. The directory is not created.
+pub fn cache_dir() -> PathBuf {
     // we use a different env var and a different default dir name to avoid
     // conflicts with the python tiktoken package, while sharing a cache dir
     // with the python tiktoken package is a desirable future goal, it is not
     // a priority and we should optimize for avoiding breaking tiktoken installs
     // on the same system until we can validate the correctness wrt the python
     // implementation and write tests to avoid regressions
-    let cache_dir_override = std::env::var("TIKTOKEN_RS_CACHE_DIR").ok();
-    if let Some(cache_dir_override) = cache_dir_override {
-        Ok(PathBuf::from(cache_dir_override))
-    } else {
-        let cache_dir = std::env::temp_dir().join("tiktoken-rs-cache");
+    match std::env::var(TIKTOKEN_RS_CACHE_DIR_VAR) {
+        Ok(cache_dir_override) => PathBuf::from(cache_dir_override),
+        Err(_) => std::env::temp_dir().join("tiktoken-rs-cache"),
+    }
+}
+
+fn resolve_cache_dir() -> Result<PathBuf, RemoteVocabFileError> {
+    let cache_dir = cache_dir();
+    if std::env::var_os(TIKTOKEN_RS_CACHE_DIR_VAR).is_none() {
         std::fs::create_dir_all(&cache_dir).map_err(|e| {
             RemoteVocabFileError::IOError(format!("creating cache dir {cache_dir:?}"), e)
         })?;
-        Ok(cache_dir)
     }
+    Ok(cache_dir)
 }
 
 fn resolve_cache_path(cache_dir: &Path, url: &str) -> PathBuf {
@@ -572,4 +668,39 @@ mod tests {
             let _ = encoding.load().unwrap();
         }
     }
+
+    #[test]
+    fn test_find_cached_file_offline() {
+        let cache_dir = std::env::temp_dir().join(format!(
+            "tiktoken-rs-cache-offline-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let url = "https://example.com/vocab.tiktoken";
+        let expected_path = resolve_cache_path(&cache_dir, url);
+
+        match find_cached_file(&cache_dir, url, None) {
+            Err(LoadError::OfflineCacheMiss {
+                url: miss_url,
+                path,
+            }) => {
+                assert_eq!(miss_url, url);
+                assert_eq!(path, expected_path);
+            }
+            other => panic!("expected a cache miss, got {other:?}"),
+        }
+
+        std::fs::write(&expected_path, b"contents").unwrap();
+        let hash = format!("{:x}", Sha256::digest(b"contents"));
+        assert_eq!(
+            find_cached_file(&cache_dir, url, Some(&hash)).unwrap(),
+            expected_path
+        );
+        assert!(matches!(
+            find_cached_file(&cache_dir, url, Some("0000")),
+            Err(LoadError::OfflineCacheMiss { .. })
+        ));
+
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
 }
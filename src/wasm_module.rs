@@ -94,6 +94,7 @@ impl JsHarmonyEncoding {
                 .map_err(|e| JsValue::from_str(&format!("invalid config: {e}")))?;
             Some(crate::encoding::RenderConversationConfig {
                 auto_drop_analysis: cfg.auto_drop_analysis.unwrap_or(true),
+                ..Default::default()
             })
         };
         self.inner
@@ -122,6 +123,7 @@ impl JsHarmonyEncoding {
                 .map_err(|e| JsValue::from_str(&format!("invalid config: {e}")))?;
             Some(crate::encoding::RenderConversationConfig {
                 auto_drop_analysis: cfg.auto_drop_analysis.unwrap_or(true),
+                ..Default::default()
             })
         };
         self.inner
@@ -153,6 +155,7 @@ impl JsHarmonyEncoding {
                 conversation_has_function_tools: cfg
                     .conversation_has_function_tools
                     .unwrap_or(false),
+                ..Default::default()
             })
         };
 
@@ -261,6 +264,26 @@ impl JsStreamableParser {
         Ok(Self { inner })
     }
 
+    /// Load the encoding called `name` and create a parser for it, so the
+    /// encoding object does not have to be kept around on the JS side.
+    /// Without a `role` the first message must start with its header.
+    #[wasm_bindgen(js_name = fromEncodingName)]
+    pub async fn from_encoding_name(
+        name: String,
+        role: Option<String>,
+    ) -> Result<JsStreamableParser, JsValue> {
+        let role = role
+            .map(|role| {
+                Role::try_from(role.as_str())
+                    .map_err(|_| JsValue::from_str(&format!("unknown role: {role}")))
+            })
+            .transpose()?;
+        let encoding = load_encoding(&name).await?;
+        let inner =
+            StreamableParser::new(encoding, role).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self { inner })
+    }
+
     pub fn process(&mut self, token: u32) -> Result<(), JsValue> {
         self.inner
             .process(token)
@@ -268,6 +291,18 @@ impl JsStreamableParser {
             .map_err(|e| JsValue::from_str(&e.to_string()))
     }
 
+    #[wasm_bindgen(js_name = processEos)]
+    pub fn process_eos(&mut self) -> Result<(), JsValue> {
+        self.inner
+            .process_eos()
+            .map(|_| ())
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn reset(&mut self) {
+        self.inner.reset();
+    }
+
     #[wasm_bindgen(getter, js_name = currentContent)]
     pub fn current_content(&self) -> Result<String, JsValue> {
         self.inner
@@ -289,12 +324,8 @@ impl JsStreamableParser {
     }
 
     #[wasm_bindgen(getter, js_name = lastContentDelta)]
-    pub fn last_content_delta(&self) -> Result<String, JsValue> {
-        match self.inner.last_content_delta() {
-            Ok(Some(s)) => Ok(s),
-            Ok(None) => Ok(String::new()),
-            Err(e) => Err(JsValue::from_str(&e.to_string())),
-        }
+    pub fn last_content_delta(&self) -> String {
+        self.inner.last_content_delta().unwrap_or_default()
     }
 
     #[wasm_bindgen(getter)]
@@ -308,6 +339,11 @@ impl JsStreamableParser {
         self.inner.tokens().to_vec()
     }
 
+    #[wasm_bindgen(getter, js_name = consumedTokens)]
+    pub fn consumed_tokens(&self) -> Vec<u32> {
+        self.inner.consumed_tokens().to_vec()
+    }
+
     #[wasm_bindgen(getter)]
     pub fn state(&self) -> Result<String, JsValue> {
         self.inner
@@ -317,12 +353,20 @@ impl JsStreamableParser {
 
     #[wasm_bindgen(getter, js_name = currentRecipient)]
     pub fn current_recipient(&self) -> String {
-        self.inner.current_recipient().unwrap_or_default()
+        self.inner
+            .current_recipient()
+            .unwrap_or_default()
+            .to_string()
     }
 
     #[wasm_bindgen(getter, js_name = currentChannel)]
     pub fn current_channel(&self) -> String {
-        self.inner.current_channel().unwrap_or_default()
+        self.inner.current_channel().unwrap_or_default().to_string()
+    }
+
+    #[wasm_bindgen(getter, js_name = isDone)]
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
     }
 }
 
@@ -341,12 +385,18 @@ pub async fn load_harmony_encoding(
     if let Some(base) = base_url {
         crate::tiktoken_ext::set_tiktoken_base_url(base);
     }
+    let encoding = load_encoding(name).await?;
+    Ok(JsHarmonyEncoding { inner: encoding })
+}
+
+async fn load_encoding(name: &str) -> Result<HarmonyEncoding, JsValue> {
     let parsed: HarmonyEncodingName = name
         .parse::<HarmonyEncodingName>()
         .map_err(|e| JsValue::from_str(&e.to_string()))?;
-    let encoding =
-        inner_load_harmony_encoding(parsed).map_err(|e| JsValue::from_str(&e.to_string()))?;
-    Ok(JsHarmonyEncoding { inner: encoding })
+    let encoding = inner_load_harmony_encoding(parsed);
+    #[cfg(target_arch = "wasm32")]
+    let encoding = encoding.await;
+    encoding.map_err(|e| JsValue::from_str(&e.to_string()))
 }
 
 #[wasm_bindgen]
@@ -819,6 +819,37 @@ def test_render_conversation_for_training_non_final():
     assert tokens_training == tokens_regular
 
 
+def test_count_conversation_tokens():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+
+    convo = Conversation.from_messages(
+        [
+            Message.from_role_and_content(Role.USER, "hi"),
+            Message.from_role_and_content(Role.ASSISTANT, "hello").with_channel(
+                "final"
+            ),
+        ]
+    )
+
+    assert encoding.count_conversation_tokens(convo) == len(
+        encoding.render_conversation(convo)
+    )
+
+
+def test_stop_tokens():
+    encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
+
+    def token(text: str) -> int:
+        return encoding.encode(text, allowed_special={text})[0]
+
+    assert encoding.stop_tokens() == sorted(
+        [token("<|end|>"), token("<|return|>"), token("<|call|>")]
+    )
+    assert encoding.stop_tokens_for_assistant_actions() == sorted(
+        [token("<|return|>"), token("<|call|>")]
+    )
+
+
 def test_decode_utf8_invalid_token():
     """Invalid tokens should raise an exception (type doesn't matter)."""
     encoding = load_harmony_encoding(HarmonyEncodingName.HARMONY_GPT_OSS)
@@ -921,9 +952,12 @@ def test_streamable_parser():
 
     tokens = encoding.encode(text_tokens, allowed_special="all")
     parser = StreamableParser(encoding, Role.ASSISTANT)
+    assert not parser.is_done
     for token in tokens:
         parser.process(token)
     assert len(parser.messages) == 3
+    assert parser.is_done
+    assert parser.consumed_tokens == tokens
 
 
 def test_streamable_parser_tool_call_with_constrain_adjacent():